@@ -1,11 +1,21 @@
 use crate::flatten;
+use crate::flatten::Reject;
+use crate::output;
+use crate::workflow::Workflow;
 use csv::Writer;
 use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::error::Error;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+// Fields are tagged with their type when serialized, like {"type": "box", "left": 1, ...}
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum FlatField {
+    #[serde(rename = "box")]
     Box_ {
         left: i32,
         top: i32,
@@ -40,14 +50,121 @@ pub enum FlatField {
     },
 }
 
+impl FlatField {
+    // The same names used for the serialized type tag
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            FlatField::Box_ { .. } => "box",
+            FlatField::Length { .. } => "length",
+            FlatField::List { .. } => "list",
+            FlatField::NoOp { .. } => "no_op",
+            FlatField::Point { .. } => "point",
+            FlatField::Same { .. } => "same",
+            FlatField::Select { .. } => "select",
+            FlatField::Text { .. } => "text",
+        }
+    }
+
+    // An empty field of the named type
+    pub fn from_type_name(name: &str) -> Option<FlatField> {
+        let field = match name {
+            "box" => FlatField::Box_ {
+                left: 0,
+                top: 0,
+                right: 0,
+                bottom: 0,
+            },
+            "length" => FlatField::Length {
+                x1: 0,
+                y1: 0,
+                x2: 0,
+                y2: 0,
+            },
+            "list" => FlatField::List {
+                values: Vec::new(),
+                value: "".to_string(),
+            },
+            "no_op" => FlatField::NoOp {
+                value: "".to_string(),
+            },
+            "point" => FlatField::Point { x: 0, y: 0 },
+            "same" => FlatField::Same {
+                value: "".to_string(),
+            },
+            "select" => FlatField::Select {
+                value: "".to_string(),
+            },
+            "text" => FlatField::Text {
+                value: "".to_string(),
+            },
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
 pub type FlatRow = IndexMap<String, FlatField>;
 
-#[derive(Debug)]
+// Header suffixes for marks that are split over several CSV columns
+const MARK_SUFFIXES: [(&str, &[&str]); 3] = [
+    ("box", &["left", "top", "right", "bottom"]),
+    ("length", &["x1", "y1", "x2", "y2"]),
+    ("point", &["x", "y"]),
+];
+
+// The subject index is rebuilt from the rows when deserializing. Rejects are not
+// kept, they're for fixing the classifications export not for reconciling.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(try_from = "FlatData")]
 pub struct Flat {
     pub workflow_id: String,
     pub workflow_name: String,
+    #[serde(serialize_with = "serialize_column_types")]
     columns: IndexMap<String, FlatField>,
     rows: Vec<FlatRow>,
+    #[serde(skip)]
+    subjects: IndexMap<String, Vec<usize>>, // Row indices per subject in first-seen order
+    #[serde(skip)]
+    rejects: Vec<Reject>,
+}
+
+// The column prototypes hold values from whichever row came first, so only write
+// their types, like {"T0: Name": "text"}
+fn serialize_column_types<S>(
+    columns: &IndexMap<String, FlatField>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_map(columns.iter().map(|(c, f)| (c, f.type_name())))
+}
+
+#[derive(Deserialize)]
+struct FlatData {
+    workflow_id: String,
+    workflow_name: String,
+    #[serde(default)]
+    columns: IndexMap<String, String>, // Column -> type name
+    rows: Vec<FlatRow>,
+}
+
+impl TryFrom<FlatData> for Flat {
+    type Error = String;
+
+    fn try_from(data: FlatData) -> Result<Self, Self::Error> {
+        let mut flat = Flat::new(&data.workflow_id, &data.workflow_name);
+        for (column, type_name) in data.columns {
+            let field_type = FlatField::from_type_name(&type_name).ok_or_else(|| {
+                format!("Unknown type \"{}\" for column \"{}\"", type_name, column)
+            })?;
+            flat.columns.insert(column, field_type);
+        }
+        for row in data.rows.iter() {
+            flat.add_row(row);
+        }
+        Ok(flat)
+    }
 }
 
 impl Flat {
@@ -57,6 +174,8 @@ impl Flat {
             workflow_name: workflow_name.to_string(),
             columns: IndexMap::new(),
             rows: Vec::new(),
+            subjects: IndexMap::new(),
+            rejects: Vec::new(),
         }
     }
 
@@ -66,41 +185,254 @@ impl Flat {
                 self.columns.insert(column.to_owned(), field.clone());
             }
         }
+        self.subjects
+            .entry(subject_key(row).to_string())
+            .or_default()
+            .push(self.rows.len());
         self.rows.push(row.to_owned());
     }
 
+    pub fn add_reject(&mut self, reject: Reject) {
+        self.rejects.push(reject);
+    }
+
+    pub fn rejects(&self) -> &[Reject] {
+        &self.rejects
+    }
+
     pub fn sort(&mut self) {
-        self.rows
-            .sort_unstable_by_key(|row| row[flatten::SUBJECT_ID].clone());
-    }
-
-    pub fn group(&self) -> IndexMap<String, Vec<FlatRow>> {
-        let mut grouped: IndexMap<String, Vec<FlatRow>> = IndexMap::new();
-        for row in &self.rows {
-            let key = match row.get(flatten::SUBJECT_ID).unwrap() {
-                FlatField::Same { value } => value,
-                _ => panic!("Missing key field."),
-            };
-            if !grouped.contains_key(key) {
-                grouped.insert(key.to_string(), Vec::new());
+        self.rows.sort_by_key(|row| subject_key(row).to_string());
+        self.index_subjects();
+    }
+
+    // Keep only the rows that pass the test, the columns stay as they are
+    pub fn retain_rows(&mut self, keep: impl FnMut(&FlatRow) -> bool) {
+        self.rows.retain(keep);
+        self.index_subjects();
+    }
+
+    fn index_subjects(&mut self) {
+        self.subjects.clear();
+        for (i, row) in self.rows.iter().enumerate() {
+            self.subjects
+                .entry(subject_key(row).to_string())
+                .or_default()
+                .push(i);
+        }
+    }
+
+    // Collect every subject's rows, keyed by subject ID in first-seen order
+    pub fn group(&self) -> IndexMap<&str, Vec<&FlatRow>> {
+        self.groups().collect()
+    }
+
+    // Lazily walk the subjects in first-seen order, borrowing each subject's rows
+    pub fn groups(&self) -> impl Iterator<Item = (&str, Vec<&FlatRow>)> + '_ {
+        self.subjects.iter().map(|(subject_id, indices)| {
+            let rows = indices.iter().map(|&i| &self.rows[i]).collect();
+            (subject_id.as_str(), rows)
+        })
+    }
+
+    pub fn rows(&self) -> &[FlatRow] {
+        &self.rows
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    // Classifications of several subjects are spread over several rows, so count
+    // their IDs. Rows without one are counted on their own.
+    pub fn classification_count(&self) -> usize {
+        let mut ids: HashSet<&str> = HashSet::new();
+        let mut unknown = 0;
+        for row in self.rows.iter() {
+            match row.get(flatten::CLASSIFICATION_ID) {
+                Some(FlatField::NoOp { value }) if !value.is_empty() => {
+                    ids.insert(value);
+                }
+                _ => unknown += 1,
             }
         }
-        grouped
+        ids.len() + unknown
+    }
+
+    pub fn subject_count(&self) -> usize {
+        self.subjects.len()
+    }
+
+    // Put the columns in a stable order no matter which classification came first:
+    // the subject ID and subject metadata, then the tasks in workflow order (or by
+    // task ID when there's no workflow), and then the classification metadata.
+    pub fn sort_columns(&mut self, workflow: Option<&Workflow>) {
+        let task_order = workflow.map(|w| w.task_order()).unwrap_or_default();
+
+        let mut columns: Vec<(ColumnKey, String, FlatField)> = self
+            .columns
+            .drain(..)
+            .map(|(column, field)| {
+                let key = column_key(&column, &field, workflow, &task_order);
+                (key, column, field)
+            })
+            .collect();
+        columns.sort_by(|a, b| a.0.cmp(&b.0));
+
+        self.columns = columns
+            .into_iter()
+            .map(|(_, column, field)| (column, field))
+            .collect();
+    }
+
+    pub fn columns(&self) -> &IndexMap<String, FlatField> {
+        &self.columns
+    }
+
+    // Read a flattened CSV written by write_csv, and maybe corrected by hand. Marks
+    // are recognized by their header suffixes. Other column types are guessed from
+    // their names unless they're given in a column types CSV.
+    pub fn read_csv(csv_path: &Path, types_csv: Option<&Path>) -> Result<Flat, Box<dyn Error>> {
+        let types = match types_csv {
+            Some(types_csv) => read_column_types(types_csv)?,
+            None => IndexMap::new(),
+        };
+
+        let mut reader = csv::Reader::from_path(csv_path)?;
+        let headers = reader.headers()?.clone();
+        let columns = csv_columns(&headers, &types);
+
+        // Keep every column, even ones that are blank in every row
+        let mut flat = Flat::new("", "");
+        for (column, field_type, _) in columns.iter() {
+            flat.columns.insert(column.clone(), field_type.clone());
+        }
+
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+
+            let mut row = FlatRow::new();
+            for (column, field_type, indices) in columns.iter() {
+                let cells: Vec<&str> = indices
+                    .iter()
+                    .map(|&i| record.get(i).unwrap_or(""))
+                    .collect();
+                let field = csv_field(field_type, &cells).map_err(|e| {
+                    format!(
+                        "Line {} column \"{}\" of {}: {}",
+                        line,
+                        column,
+                        csv_path.display(),
+                        e
+                    )
+                })?;
+                if let Some(field) = field {
+                    row.insert(column.clone(), field);
+                }
+            }
+            flat.add_row(&row);
+        }
+        Ok(flat)
+    }
+
+    // The type of each flattened CSV column so that read_csv doesn't have to guess
+    pub fn write_column_types_csv(&self, csv_path: &Path) -> Result<(), Box<dyn Error>> {
+        output::write_atomic(csv_path, |output| {
+            let mut writer = Writer::from_writer(output);
+
+            writer.write_record(["column", "type"])?;
+
+            for (column, field_type) in self.columns.iter() {
+                writer.write_record([column.as_str(), field_type.type_name()])?;
+            }
+
+            writer.flush()?;
+            Ok(())
+        })
     }
 
     pub fn write_csv(&self, csv_path: &Path) -> Result<(), Box<dyn Error>> {
-        let mut writer =
-            Writer::from_path(csv_path).expect("Could not write to the unreconciled CSV file");
+        output::write_atomic(csv_path, |output| {
+            let mut writer = Writer::from_writer(output);
 
-        let mut output = self.csv_header();
-        writer.write_record(output)?;
+            writer.write_record(self.csv_header())?;
 
-        for row in self.rows.iter() {
-            output = self.csv_row(row);
-            writer.write_record(output)?;
+            for row in self.rows.iter() {
+                writer.write_record(self.csv_row(row))?;
+            }
+
+            writer.flush()?;
+            Ok(())
+        })
+    }
+
+    // Read a flattened JSON document written by write_json
+    pub fn read_json(json_path: &Path) -> Result<Flat, Box<dyn Error>> {
+        let reader = BufReader::new(File::open(json_path)?);
+        let flat: Flat = serde_json::from_reader(reader)?;
+        Ok(flat)
+    }
+
+    // Read JSON Lines written by write_jsonl. There's no workflow or column list in
+    // them so the columns come from the rows in the usual order.
+    pub fn read_jsonl(
+        jsonl_path: &Path,
+        workflow: Option<&Workflow>,
+    ) -> Result<Flat, Box<dyn Error>> {
+        let reader = BufReader::new(File::open(jsonl_path)?);
+        let mut flat = Flat::new("", "");
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row: FlatRow = serde_json::from_str(&line)
+                .map_err(|e| format!("Line {} of {}: {}", i + 1, jsonl_path.display(), e))?;
+            flat.add_row(&row);
         }
 
-        Ok(())
+        flat.sort_columns(workflow);
+        Ok(flat)
+    }
+
+    // One JSON document with the workflow, columns, and every row
+    pub fn write_json(&self, json_path: &Path) -> Result<(), Box<dyn Error>> {
+        output::write_atomic(json_path, |output| {
+            serde_json::to_writer_pretty(&mut *output, self)?;
+            writeln!(output)?;
+            Ok(())
+        })
+    }
+
+    // JSON Lines, one row object per line
+    pub fn write_jsonl(&self, jsonl_path: &Path) -> Result<(), Box<dyn Error>> {
+        output::write_atomic(jsonl_path, |output| {
+            for row in self.rows.iter() {
+                serde_json::to_writer(&mut *output, row)?;
+                writeln!(output)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn write_rejects_csv(&self, csv_path: &Path) -> Result<(), Box<dyn Error>> {
+        output::write_atomic(csv_path, |output| {
+            let mut writer = Writer::from_writer(output);
+
+            writer.write_record(["line", flatten::CLASSIFICATION_ID, "reason"])?;
+
+            for reject in self.rejects.iter() {
+                writer.write_record([
+                    reject.line.to_string(),
+                    reject.classification_id.clone(),
+                    reject.reason.clone(),
+                ])?;
+            }
+
+            writer.flush()?;
+            Ok(())
+        })
     }
 
     fn csv_header(&self) -> Vec<String> {
@@ -147,9 +479,10 @@ impl Flat {
     fn csv_row(&self, row: &FlatRow) -> Vec<String> {
         let mut output: Vec<String> = Vec::new();
 
-        for (header, _) in self.columns.iter() {
+        for (header, field_type) in self.columns.iter() {
             if !row.contains_key(header) {
-                output.push("".to_string());
+                let width = column_width(field_type);
+                output.extend(std::iter::repeat_n("".to_string(), width));
             } else {
                 let field: &FlatField = row.get(header).unwrap();
                 match field {
@@ -195,3 +528,315 @@ impl Flat {
         output
     }
 }
+
+fn read_column_types(csv_path: &Path) -> Result<IndexMap<String, FlatField>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(csv_path)?;
+    let mut types = IndexMap::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let column = record.get(0).unwrap_or("").to_string();
+        let type_name = record.get(1).unwrap_or("").trim();
+        match FlatField::from_type_name(type_name) {
+            Some(field_type) => {
+                types.insert(column, field_type);
+            }
+            None => {
+                return Err(format!(
+                    "Unknown type \"{}\" for column \"{}\" in {}",
+                    type_name,
+                    column,
+                    csv_path.display()
+                )
+                .into())
+            }
+        }
+    }
+    Ok(types)
+}
+
+// Map the CSV headers back onto columns: the column name, its type, and the
+// indices of the CSV cells that hold its value
+fn csv_columns(
+    headers: &csv::StringRecord,
+    types: &IndexMap<String, FlatField>,
+) -> Vec<(String, FlatField, Vec<usize>)> {
+    let mut columns = Vec::new();
+    let mut used = vec![false; headers.len()];
+
+    for (i, header) in headers.iter().enumerate() {
+        if used[i] {
+            continue;
+        }
+
+        let mark = MARK_SUFFIXES.iter().find_map(|(type_name, suffixes)| {
+            let base = header.strip_suffix(&format!(": {}", suffixes[0]))?;
+            let indices: Option<Vec<usize>> = suffixes
+                .iter()
+                .map(|suffix| {
+                    let name = format!("{}: {}", base, suffix);
+                    headers.iter().position(|h| h == name).filter(|&j| !used[j])
+                })
+                .collect();
+            Some((base, FlatField::from_type_name(type_name)?, indices?))
+        });
+
+        match mark {
+            Some((base, field_type, indices)) => {
+                indices.iter().for_each(|&j| used[j] = true);
+                columns.push((base.to_string(), field_type, indices));
+            }
+            None => {
+                used[i] = true;
+                let field_type = types
+                    .get(header)
+                    .cloned()
+                    .unwrap_or_else(|| guess_column_type(header));
+                columns.push((header.to_string(), field_type, vec![i]));
+            }
+        }
+    }
+    columns
+}
+
+// Where the column types of a flattened CSV go, "flat.csv" -> "flat.types.csv"
+pub fn column_types_path(csv_path: &Path) -> PathBuf {
+    csv_path.with_extension("types.csv")
+}
+
+// Task columns look like "T12: label"
+pub fn is_task_column(column: &str) -> bool {
+    let task_id = column.split_once(": ").map_or("", |(task_id, _)| task_id);
+    task_id.len() > 1
+        && task_id.starts_with('T')
+        && task_id[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+// Task columns look like "T12: label", the classification metadata has fixed
+// names, and anything else came from the subject data
+fn guess_column_type(column: &str) -> FlatField {
+    let no_op = [
+        flatten::CLASSIFICATION_ID,
+        flatten::USER_NAME,
+        flatten::GOLD_STD,
+        flatten::EXPERT,
+        flatten::WORKFLOW_VER,
+        flatten::STARTED_AT,
+        flatten::FINISHED_AT,
+    ];
+    let value = "".to_string();
+    if no_op.contains(&column) {
+        FlatField::NoOp { value }
+    } else if is_task_column(column) {
+        FlatField::Text { value }
+    } else {
+        FlatField::Same { value }
+    }
+}
+
+// Build a field from its CSV cells. Marks with every cell blank were not drawn,
+// and blank subject data and metadata were missing from the classification.
+fn csv_field(field_type: &FlatField, cells: &[&str]) -> Result<Option<FlatField>, String> {
+    let is_absent = matches!(
+        field_type,
+        FlatField::Box_ { .. }
+            | FlatField::Length { .. }
+            | FlatField::Point { .. }
+            | FlatField::Same { .. }
+            | FlatField::NoOp { .. }
+    );
+    if is_absent && cells.iter().all(|c| c.trim().is_empty()) {
+        return Ok(None);
+    }
+
+    let number = |i: usize| -> Result<i32, String> {
+        let cell = cells[i].trim();
+        cell.parse::<f32>()
+            .map(|n| n.round() as i32)
+            .map_err(|_| format!("\"{}\" is not a number", cell))
+    };
+    let value = cells[0].to_string();
+
+    let field = match field_type {
+        FlatField::Box_ { .. } => FlatField::Box_ {
+            left: number(0)?,
+            top: number(1)?,
+            right: number(2)?,
+            bottom: number(3)?,
+        },
+        FlatField::Length { .. } => FlatField::Length {
+            x1: number(0)?,
+            y1: number(1)?,
+            x2: number(2)?,
+            y2: number(3)?,
+        },
+        FlatField::Point { .. } => FlatField::Point {
+            x: number(0)?,
+            y: number(1)?,
+        },
+        FlatField::List { .. } => FlatField::List {
+            values: value
+                .split(", ")
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_string())
+                .collect(),
+            value,
+        },
+        FlatField::NoOp { .. } => FlatField::NoOp { value },
+        FlatField::Same { .. } => FlatField::Same { value },
+        FlatField::Select { .. } => FlatField::Select { value },
+        FlatField::Text { .. } => FlatField::Text { value },
+    };
+    Ok(Some(field))
+}
+
+fn column_width(field_type: &FlatField) -> usize {
+    match field_type {
+        FlatField::Box_ { .. } | FlatField::Length { .. } => 4,
+        FlatField::Point { .. } => 2,
+        _ => 1,
+    }
+}
+
+// The column for the nth mark drawn with the same tool: "T0: box", "T0: box #2", ...
+pub fn mark_key(base: &str, n: usize) -> String {
+    if n <= 1 {
+        base.to_string()
+    } else {
+        format!("{} #{}", base, n)
+    }
+}
+
+// The inverse of `mark_key`, strips any mark number from the column name
+pub fn mark_base(column: &str) -> &str {
+    match column.rsplit_once(" #") {
+        Some((base, n)) if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => column,
+    }
+}
+
+// Column sort key: group, task position, task ID, label position, label, mark number
+type ColumnKey = (u8, usize, Vec<Chunk>, usize, Vec<Chunk>, usize);
+
+fn column_key(
+    column: &str,
+    field: &FlatField,
+    workflow: Option<&Workflow>,
+    task_order: &[String],
+) -> ColumnKey {
+    let group = match field {
+        _ if column == flatten::SUBJECT_ID => 0,
+        FlatField::Same { .. } => 1,
+        FlatField::NoOp { .. } => 3,
+        _ => 2,
+    };
+    if group != 2 {
+        // The NoOp columns are always added in the same order so leave them be
+        let name = if group == 3 { "" } else { column };
+        return (group, 0, natural_key(name), 0, Vec::new(), 0);
+    }
+
+    let base = match field {
+        FlatField::Box_ { .. } | FlatField::Length { .. } | FlatField::Point { .. } => {
+            mark_base(column)
+        }
+        _ => column,
+    };
+    let mark: usize = column[base.len()..]
+        .trim_start_matches(" #")
+        .parse()
+        .unwrap_or(1);
+    let (task_id, label) = base.split_once(": ").unwrap_or((base, ""));
+
+    let task_rank = task_order
+        .iter()
+        .position(|t| t == task_id)
+        .unwrap_or(task_order.len());
+    let labels = workflow.map(|w| w.label_order(task_id)).unwrap_or_default();
+    let label_rank = labels
+        .iter()
+        .position(|l| *l == label)
+        .unwrap_or(labels.len());
+
+    (
+        group,
+        task_rank,
+        natural_key(task_id),
+        label_rank,
+        natural_key(label),
+        mark,
+    )
+}
+
+// Pieces of a string for natural sorting, so that "T2" comes before "T10"
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Chunk {
+    Number(u64),
+    Text(String),
+}
+
+fn natural_key(value: &str) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        let is_digit = c.is_ascii_digit();
+        let end = rest
+            .find(|ch: char| ch.is_ascii_digit() != is_digit)
+            .unwrap_or(rest.len());
+        let (piece, tail) = rest.split_at(end);
+        chunks.push(match piece.parse() {
+            Ok(number) if is_digit => Chunk::Number(number),
+            _ => Chunk::Text(piece.to_string()),
+        });
+        rest = tail;
+    }
+    chunks
+}
+
+fn subject_key(row: &FlatRow) -> &str {
+    match row.get(flatten::SUBJECT_ID) {
+        Some(FlatField::Same { value }) => value,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flatten::{flatten_reader, FlattenOptions};
+    use crate::reconcile::ReconcileOptions;
+    use crate::reconciled::Reconciled;
+    use std::fs;
+
+    // Subject 12's last classification has no "a" in its subject data
+    const CLASSIFICATIONS: &str = r#"classification_id,user_name,workflow_id,workflow_name,workflow_version,metadata,annotations,subject_data,subject_ids
+1,u1,5,W,1.1,{},"[{""task"": ""T0"", ""task_label"": ""Name"", ""value"": ""x""}, {""task"": ""T1"", ""task_label"": ""Habitat"", ""value"": [""forest"", ""swamp""]}, {""task"": ""T2"", ""value"": [{""tool"": 0, ""tool_label"": ""Box"", ""x"": 1, ""y"": 2, ""width"": 3, ""height"": 4}]}]","{""11"": {""Filename"": ""f11.jpg"", ""a"": ""1""}}",11
+2,u2,5,W,1.1,{},"[{""task"": ""T0"", ""task_label"": ""Name"", ""value"": ""y""}, {""task"": ""T1"", ""task_label"": ""Habitat"", ""value"": [""forest""]}]","{""12"": {""Filename"": ""f12.jpg"", ""a"": ""1""}}",12
+3,u3,5,W,1.1,{},"[{""task"": ""T0"", ""task_label"": ""Name"", ""value"": ""y""}, {""task"": ""T1"", ""task_label"": ""Habitat"", ""value"": [""swamp""]}]","{""12"": {""Filename"": ""f12.jpg""}}",12
+4,u4,5,W,1.1,{},"[{""task"": ""T0"", ""task_label"": ""Name"", ""value"": """"}, {""task"": ""T2"", ""value"": [{""tool"": 0, ""tool_label"": ""Box"", ""x"": 5, ""y"": 6, ""width"": 7, ""height"": 8}]}]","{""11"": {""Filename"": ""f11.jpg"", ""a"": ""1""}}",11
+"#;
+
+    fn reconciled_rows(flat: &Flat) -> serde_json::Value {
+        let mut reconciled = Reconciled::new(&flat.workflow_id, &flat.workflow_name);
+        reconciled.reconcile(flat, &ReconcileOptions::default());
+        serde_json::to_value(reconciled.rows()).unwrap()
+    }
+
+    #[test]
+    fn read_csv_reconciles_like_the_classifications() {
+        let options = FlattenOptions::default();
+        let flat = flatten_reader(CLASSIFICATIONS.as_bytes(), &options).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("reconcile-flat-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let csv_path = dir.join("flat.csv");
+        let types_path = dir.join("types.csv");
+        flat.write_csv(&csv_path).unwrap();
+        flat.write_column_types_csv(&types_path).unwrap();
+
+        let read = Flat::read_csv(&csv_path, Some(&types_path)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(reconciled_rows(&read), reconciled_rows(&flat));
+    }
+}
@@ -1,13 +1,22 @@
 use crate::flat;
+use crate::workflow;
+use crate::workflow::{TaskKind, ToolKind, Workflow, Workflows};
+use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
-use std::path::{Path, PathBuf};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 
 // Known fields to extract or use
 pub const ANNOTATIONS: &str = "annotations";
 pub const CLASSIFICATION_ID: &str = "classification_id";
+pub const CREATED_AT: &str = "created_at";
 pub const EXPERT: &str = "expert";
 pub const FINISHED_AT: &str = "finished_at";
 pub const GOLD_STD: &str = "gold_standard";
@@ -17,6 +26,8 @@ pub const SUBJECT_DATA: &str = "subject_data";
 pub const SUBJECT_ID: &str = "subject_id";
 pub const SUBJECT_IDS: &str = "subject_ids";
 pub const USER_NAME: &str = "user_name";
+pub const WORKFLOW_ID: &str = "workflow_id";
+pub const WORKFLOW_NAME: &str = "workflow_name";
 pub const WORKFLOW_VER: &str = "workflow_version";
 
 #[derive(Deserialize)]
@@ -40,6 +51,7 @@ struct LengthField {
 #[derive(Deserialize)]
 struct ListField {
     task_label: String,
+    #[serde(rename = "value")]
     values: Vec<String>,
 }
 
@@ -62,74 +74,517 @@ struct TextField {
     value: Option<String>,
 }
 
-pub fn flatten(
-    classifications_csv: &PathBuf,
-    workflow_id: &Option<String>,
-) -> Result<flat::Flat, Box<dyn Error>> {
-    let mut reader = csv::Reader::from_path(classifications_csv)
-        .expect("Could not read the classifications CSV file");
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub line: u64,
+    pub classification_id: String,
+}
 
-    let workflow_id = get_workflow_id(workflow_id, classifications_csv)?;
-    let workflow_name = get_workflow_name(classifications_csv)?;
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.classification_id.is_empty() {
+            write!(f, "line {}", self.line)
+        } else {
+            write!(
+                f,
+                "line {} (classification {})",
+                self.line, self.classification_id
+            )
+        }
+    }
+}
 
-    let mut flat = flat::Flat::new(&workflow_id, &workflow_name);
+#[derive(Debug)]
+pub enum FlattenError {
+    Csv(csv::Error),
+    MissingColumn {
+        at: Location,
+        column: String,
+    },
+    Json {
+        at: Location,
+        column: String,
+        source: serde_json::Error,
+    },
+    Annotation {
+        at: Location,
+        task: String,
+        message: String,
+    },
+    InvalidVersion {
+        at: Location,
+        version: String,
+    },
+    UnknownVersion {
+        at: Location,
+        workflow_id: String,
+        version: WorkflowVersion,
+    },
+    MultipleWorkflows {
+        ids: Vec<String>,
+    },
+    NoClassifications,
+}
 
-    for deserialized_row in reader.deserialize() {
-        let raw_row: HashMap<String, String> = deserialized_row?;
+impl fmt::Display for FlattenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlattenError::Csv(err) => write!(f, "Could not read the classifications CSV: {}", err),
+            FlattenError::MissingColumn { at, column } => {
+                write!(f, "Missing the \"{}\" column on {}", column, at)
+            }
+            FlattenError::Json { at, column, source } => {
+                write!(
+                    f,
+                    "Could not parse the \"{}\" JSON on {}: {}",
+                    column, at, source
+                )
+            }
+            FlattenError::Annotation { at, task, message } => {
+                write!(
+                    f,
+                    "Bad annotation for task \"{}\" on {}: {}",
+                    task, at, message
+                )
+            }
+            FlattenError::InvalidVersion { at, version } => {
+                write!(f, "Invalid workflow version \"{}\" on {}", version, at)
+            }
+            FlattenError::UnknownVersion {
+                at,
+                workflow_id,
+                version,
+            } => write!(
+                f,
+                "Workflow {} version {} on {} is not in the workflow CSV",
+                workflow_id, version, at
+            ),
+            FlattenError::MultipleWorkflows { ids } => write!(
+                f,
+                "More than 1 workflow in this file, you must provide a workflow ID \
+                (the workflows command lists them): {}",
+                ids.join(", ")
+            ),
+            FlattenError::NoClassifications => {
+                write!(f, "There are no classifications for this workflow")
+            }
+        }
+    }
+}
 
-        let mut flat_row = flat::FlatRow::new();
+impl Error for FlattenError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlattenError::Csv(err) => Some(err),
+            FlattenError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
-        flat_row.insert(
-            SUBJECT_ID.to_string(),
-            flat::FlatField::Same {
-                value: raw_row[SUBJECT_IDS].clone(),
-            },
-        );
+impl From<csv::Error> for FlattenError {
+    fn from(err: csv::Error) -> Self {
+        FlattenError::Csv(err)
+    }
+}
 
-        let annotations: Value = serde_json::from_str(&raw_row[ANNOTATIONS])?;
+type RawRow = HashMap<String, String>;
 
-        match annotations {
-            Value::Array(tasks) => {
-                for task in tasks {
-                    flatten_tasks(&task, "", &mut flat_row);
+// A classification row that was skipped in lenient mode
+#[derive(Debug)]
+pub struct Reject {
+    pub line: u64,
+    pub classification_id: String,
+    pub reason: String,
+}
+
+impl From<&FlattenError> for Reject {
+    fn from(err: &FlattenError) -> Self {
+        let (line, classification_id) = match err {
+            FlattenError::Csv(err) => (err.position().map_or(0, |p| p.line()), "".to_string()),
+            FlattenError::MissingColumn { at, .. }
+            | FlattenError::Json { at, .. }
+            | FlattenError::Annotation { at, .. }
+            | FlattenError::InvalidVersion { at, .. }
+            | FlattenError::UnknownVersion { at, .. } => (at.line, at.classification_id.clone()),
+            _ => (0, "".to_string()),
+        };
+        Reject {
+            line,
+            classification_id,
+            reason: err.to_string(),
+        }
+    }
+}
+
+// Workflow versions look like "12.34", a major and a minor version number
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct WorkflowVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl FromStr for WorkflowVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid workflow version \"{}\", expected MAJOR.MINOR", s);
+        let (major, minor) = s.trim().split_once('.').unwrap_or((s.trim(), "0"));
+        Ok(WorkflowVersion {
+            major: major.parse().map_err(|_| error())?,
+            minor: minor.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+impl fmt::Display for WorkflowVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FlattenOptions {
+    pub workflow_id: Option<String>,
+    pub workflows: Workflows, // From the workflow CSV
+    pub min_version: Option<WorkflowVersion>,
+    pub max_version: Option<WorkflowVersion>,
+    pub lenient: bool, // Skip bad rows instead of stopping
+}
+
+// Flatten the classifications CSV file, a path of "-" reads from stdin
+pub fn flatten(
+    classifications_csv: &Path,
+    options: &FlattenOptions,
+) -> Result<flat::Flat, FlattenError> {
+    if classifications_csv == Path::new("-") {
+        flatten_reader(io::stdin().lock(), options)
+    } else {
+        let file = File::open(classifications_csv).map_err(csv::Error::from)?;
+        flatten_reader(file, options)
+    }
+}
+
+// Flatten classifications in a single streaming pass. If no workflow ID is given
+// we use the first one we see, and complain if there are others.
+pub fn flatten_reader<R: io::Read>(
+    input: R,
+    options: &FlattenOptions,
+) -> Result<flat::Flat, FlattenError> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+
+    let workflow_column = headers.iter().position(|h| h == WORKFLOW_ID);
+    let name_column = headers.iter().position(|h| h == WORKFLOW_NAME);
+
+    let chosen_id = options.workflow_id.clone().unwrap_or_default();
+    let mut flat = flat::Flat::new(&chosen_id, "");
+    let mut other_ids: BTreeSet<String> = BTreeSet::new();
+
+    for record in reader.records() {
+        // Exports often hold every workflow in the project, skip the other ones
+        if let (Ok(record), Some(i)) = (&record, workflow_column) {
+            let id = record.get(i).unwrap_or_default();
+            if flat.workflow_id.is_empty() {
+                flat.workflow_id = id.to_string();
+            }
+            if id != flat.workflow_id {
+                if options.workflow_id.is_none() {
+                    other_ids.insert(id.to_string());
                 }
+                continue;
+            }
+            if flat.workflow_name.is_empty() {
+                let name = name_column.and_then(|n| record.get(n));
+                flat.workflow_name = name.unwrap_or_default().to_string();
             }
-            _ => panic!("No annotations in this CSV row: {:?}", annotations),
         }
 
-        let targets = [CLASSIFICATION_ID, USER_NAME, GOLD_STD, EXPERT, WORKFLOW_VER];
-        for target in targets {
-            if raw_row.contains_key(target) {
-                flat_row.insert(
-                    target.to_string(),
-                    flat::FlatField::NoOp {
-                        value: raw_row[target].clone(),
-                    },
-                );
+        // We already know we'll fail so only keep looking for workflow IDs
+        if !other_ids.is_empty() {
+            continue;
+        }
+
+        let flattened = record.map_err(FlattenError::from).and_then(|record| {
+            if !in_version_range(&record, &headers, options)? {
+                return Ok(None);
             }
+            let workflow = workflow_for(&record, &headers, &flat.workflow_id, options)?;
+            flatten_row(&record, &headers, workflow).map(Some)
+        });
+
+        match flattened {
+            Ok(Some(flat_rows)) => flat_rows.iter().for_each(|row| flat.add_row(row)),
+            Ok(None) => {}
+            Err(err) if options.lenient => flat.add_reject(Reject::from(&err)),
+            Err(err) => return Err(err),
         }
+    }
 
-        let metadata: HashMap<String, Value> = serde_json::from_str(&raw_row[METADATA])?;
+    if !other_ids.is_empty() {
+        other_ids.insert(flat.workflow_id);
+        return Err(FlattenError::MultipleWorkflows {
+            ids: other_ids.into_iter().collect(),
+        });
+    }
 
-        for target in [STARTED_AT, FINISHED_AT] {
-            if metadata.contains_key(target) {
-                flat_row.insert(
-                    target.to_string(),
-                    flat::FlatField::NoOp {
-                        value: metadata[target].to_string().trim_matches('"').to_string(),
-                    },
-                );
+    if flat.row_count() == 0 && flat.rejects().is_empty() {
+        return Err(FlattenError::NoClassifications);
+    }
+
+    flat.sort_columns(options.workflows.latest(&flat.workflow_id));
+
+    Ok(flat)
+}
+
+// A workflow found in a classifications export
+#[derive(Debug)]
+pub struct WorkflowSummary {
+    pub workflow_id: String,
+    pub workflow_name: String,
+    pub versions: BTreeSet<WorkflowVersion>,
+    pub classification_count: usize,
+    pub subject_count: usize,
+    pub first_created: String,
+    pub last_created: String,
+}
+
+// Scan the classifications export for its workflows without flattening anything
+pub fn list_workflows(classifications_csv: &Path) -> Result<Vec<WorkflowSummary>, FlattenError> {
+    if classifications_csv == Path::new("-") {
+        list_workflows_reader(io::stdin().lock())
+    } else {
+        let file = File::open(classifications_csv).map_err(csv::Error::from)?;
+        list_workflows_reader(file)
+    }
+}
+
+pub fn list_workflows_reader<R: io::Read>(input: R) -> Result<Vec<WorkflowSummary>, FlattenError> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let workflow_column = column(WORKFLOW_ID);
+    let name_column = column(WORKFLOW_NAME);
+    let version_column = column(WORKFLOW_VER);
+    let subject_column = column(SUBJECT_IDS);
+    let created_column = column(CREATED_AT);
+
+    let mut workflows: IndexMap<String, WorkflowSummary> = IndexMap::new();
+    let mut subjects: HashMap<String, BTreeSet<String>> = HashMap::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let get = |i: Option<usize>| i.and_then(|i| record.get(i)).unwrap_or_default();
+
+        let workflow_id = get(workflow_column);
+        let workflow =
+            workflows
+                .entry(workflow_id.to_string())
+                .or_insert_with(|| WorkflowSummary {
+                    workflow_id: workflow_id.to_string(),
+                    workflow_name: get(name_column).to_string(),
+                    versions: BTreeSet::new(),
+                    classification_count: 0,
+                    subject_count: 0,
+                    first_created: "".to_string(),
+                    last_created: "".to_string(),
+                });
+
+        workflow.classification_count += 1;
+
+        if let Ok(version) = get(version_column).parse() {
+            workflow.versions.insert(version);
+        }
+
+        subjects.entry(workflow_id.to_string()).or_default().extend(
+            split_subject_ids(get(subject_column))
+                .iter()
+                .map(|id| id.to_string()),
+        );
+
+        // Export timestamps look like "2020-01-01 00:00:00 UTC" so they sort as strings
+        let created = get(created_column);
+        if !created.is_empty() {
+            if workflow.first_created.is_empty() || created < workflow.first_created.as_str() {
+                workflow.first_created = created.to_string();
+            }
+            if created > workflow.last_created.as_str() {
+                workflow.last_created = created.to_string();
             }
         }
+    }
+
+    for (workflow_id, workflow) in workflows.iter_mut() {
+        workflow.subject_count = subjects.get(workflow_id).map_or(0, |s| s.len());
+    }
+
+    // Workflow IDs are numbers so shorter ones come first
+    workflows.sort_by(|a, _, b, _| (a.len(), a).cmp(&(b.len(), b)));
+    Ok(workflows.into_values().collect())
+}
+
+// Task structures change between workflow versions so we may only want some of them
+fn in_version_range(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    options: &FlattenOptions,
+) -> Result<bool, FlattenError> {
+    if options.min_version.is_none() && options.max_version.is_none() {
+        return Ok(true);
+    }
+    Ok(version_in_range(row_version(record, headers)?, options))
+}
 
-        let subject_data: HashMap<String, Value> = serde_json::from_str(&raw_row[SUBJECT_DATA])
-            .expect("Could not parse the subject_data field");
+// Answers are indices into the workflow's answer list, and the list can change
+// when the workflow is edited, so use the version the classification was made
+// with. Without a workflow CSV we make do with the labels in the annotations.
+fn workflow_for<'a>(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    workflow_id: &str,
+    options: &'a FlattenOptions,
+) -> Result<Option<&'a Workflow>, FlattenError> {
+    if !options.workflows.contains(workflow_id) {
+        return Ok(None);
+    }
+    let version = row_version(record, headers)?;
+    match options.workflows.find(workflow_id, version) {
+        Some(workflow) => Ok(Some(workflow)),
+        None => Err(FlattenError::UnknownVersion {
+            at: record_location(record, headers),
+            workflow_id: workflow_id.to_string(),
+            version,
+        }),
+    }
+}
 
-        for values in subject_data.values() {
+fn row_version(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+) -> Result<WorkflowVersion, FlattenError> {
+    let raw_version = headers
+        .iter()
+        .position(|h| h == WORKFLOW_VER)
+        .and_then(|i| record.get(i))
+        .unwrap_or_default();
+
+    raw_version
+        .parse()
+        .map_err(|_| FlattenError::InvalidVersion {
+            at: record_location(record, headers),
+            version: raw_version.to_string(),
+        })
+}
+
+fn record_location(record: &csv::StringRecord, headers: &csv::StringRecord) -> Location {
+    Location {
+        line: record.position().map_or(0, |p| p.line()),
+        classification_id: headers
+            .iter()
+            .position(|h| h == CLASSIFICATION_ID)
+            .and_then(|i| record.get(i))
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+pub fn version_in_range(version: WorkflowVersion, options: &FlattenOptions) -> bool {
+    options.min_version.is_none_or(|min| version >= min)
+        && options.max_version.is_none_or(|max| version <= max)
+}
+
+// A classification may cover several subjects, each one gets its own copy of the row
+fn flatten_row(
+    record: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    workflow: Option<&Workflow>,
+) -> Result<Vec<flat::FlatRow>, FlattenError> {
+    let raw_row: RawRow = record.deserialize(Some(headers))?;
+
+    let at = Location {
+        line: record.position().map_or(0, |p| p.line()),
+        classification_id: raw_row.get(CLASSIFICATION_ID).cloned().unwrap_or_default(),
+    };
+
+    let subject_ids = split_subject_ids(get_column(&raw_row, SUBJECT_IDS, &at)?);
+
+    // The subject ID is filled in per subject below, this keeps it the first column
+    let mut flat_row = flat::FlatRow::new();
+    flat_row.insert(
+        SUBJECT_ID.to_string(),
+        flat::FlatField::Same {
+            value: "".to_string(),
+        },
+    );
+
+    let annotations: Value = parse_json(&raw_row, ANNOTATIONS, &at)?;
+
+    match annotations {
+        Value::Array(tasks) => {
+            for task in tasks {
+                flatten_tasks(&task, "", &mut flat_row, &at, workflow)?;
+            }
+        }
+        _ => {
+            return Err(FlattenError::Annotation {
+                at,
+                task: "".to_string(),
+                message: "The annotations are not a list of tasks".to_string(),
+            })
+        }
+    }
+
+    let targets = [CLASSIFICATION_ID, USER_NAME, GOLD_STD, EXPERT, WORKFLOW_VER];
+    for target in targets {
+        if raw_row.contains_key(target) {
+            flat_row.insert(
+                target.to_string(),
+                flat::FlatField::NoOp {
+                    value: raw_row[target].clone(),
+                },
+            );
+        }
+    }
+
+    let metadata: HashMap<String, Value> = parse_json(&raw_row, METADATA, &at)?;
+
+    for target in [STARTED_AT, FINISHED_AT] {
+        if metadata.contains_key(target) {
+            flat_row.insert(
+                target.to_string(),
+                flat::FlatField::NoOp {
+                    value: metadata[target].to_string().trim_matches('"').to_string(),
+                },
+            );
+        }
+    }
+
+    let subject_data: HashMap<String, Value> = parse_json(&raw_row, SUBJECT_DATA, &at)?;
+
+    let mut flat_rows = Vec::new();
+    for subject_id in subject_ids.iter() {
+        let mut subject_row = flat_row.clone();
+        subject_row.insert(
+            SUBJECT_ID.to_string(),
+            flat::FlatField::Same {
+                value: subject_id.to_string(),
+            },
+        );
+
+        // Subject data is keyed by subject ID. When there's only one subject and
+        // the keys don't match we take whatever is there, like we always have.
+        let data: Vec<&Value> = match subject_data.get(*subject_id) {
+            Some(values) => vec![values],
+            None if subject_ids.len() == 1 => subject_data.values().collect(),
+            None => Vec::new(),
+        };
+
+        for values in data {
             if let Value::Object(obj) = values {
                 for (header, value) in obj {
                     if header != "retired" {
-                        flat_row.insert(
+                        subject_row.insert(
                             header.to_string(),
                             flat::FlatField::Same {
                                 value: value.to_string().trim_matches('"').to_string(),
@@ -139,19 +594,79 @@ pub fn flatten(
                 }
             }
         }
-        flat.add_row(&flat_row);
+        flat_rows.push(subject_row);
     }
+    Ok(flat_rows)
+}
 
-    Ok(flat)
+// Classifications of several subjects at once list them like "123,456" or "123;456"
+fn split_subject_ids(raw: &str) -> Vec<&str> {
+    let ids: Vec<&str> = raw
+        .split([',', ';'])
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .collect();
+    if ids.is_empty() {
+        vec![raw.trim()]
+    } else {
+        ids
+    }
+}
+
+fn get_column<'a>(
+    raw_row: &'a RawRow,
+    column: &str,
+    at: &Location,
+) -> Result<&'a String, FlattenError> {
+    raw_row
+        .get(column)
+        .ok_or_else(|| FlattenError::MissingColumn {
+            at: at.clone(),
+            column: column.to_string(),
+        })
+}
+
+fn parse_json<T: DeserializeOwned>(
+    raw_row: &RawRow,
+    column: &str,
+    at: &Location,
+) -> Result<T, FlattenError> {
+    serde_json::from_str(get_column(raw_row, column, at)?).map_err(|source| FlattenError::Json {
+        at: at.clone(),
+        column: column.to_string(),
+        source,
+    })
 }
 
-fn flatten_tasks(task: &Value, task_id: &str, flat_row: &mut flat::FlatRow) {
-    let task_id = get_task_id(task, &task_id);
+fn flatten_tasks(
+    task: &Value,
+    task_id: &str,
+    flat_row: &mut flat::FlatRow,
+    at: &Location,
+    workflow: Option<&Workflow>,
+) -> Result<(), FlattenError> {
+    let task_id = get_task_id(task, task_id);
+
+    // With the workflow we know what kind of task this is and don't have to guess
+    if task.get("task").is_some() {
+        if let Some(spec) = workflow.and_then(|w| w.tasks.get(&task_id)) {
+            if flatten_workflow_task(task, spec, &task_id, flat_row, at, workflow)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Turn a malformed task into an error that points at the row and task
+    let invalid = |message: String| FlattenError::Annotation {
+        at: at.clone(),
+        task: task_id.clone(),
+        message,
+    };
 
     if let Value::Object(obj) = task {
         if obj.contains_key("value") && obj["value"].is_array() && obj["value"][0].is_string() {
-            let mut field: ListField =
-                serde_json::from_value(task.clone()).expect("Invalid list field");
+            let mut field: ListField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid list field: {}", e)))?;
             field.values.sort();
             let joined = field
                 .values
@@ -169,44 +684,36 @@ fn flatten_tasks(task: &Value, task_id: &str, flat_row: &mut flat::FlatRow) {
             );
         } else if obj.contains_key("value") && obj["value"].is_array() {
             let mut task_id = get_task_id(task, &task_id);
-            match &task["value"] {
-                Value::Array(subtasks) => {
-                    for subtask in subtasks {
-                        task_id = get_task_id(subtask, &task_id);
-                        flatten_tasks(subtask, &task_id, flat_row);
-                    }
+            if let Value::Array(subtasks) = &task["value"] {
+                for subtask in subtasks {
+                    task_id = get_task_id(subtask, &task_id);
+                    flatten_tasks(subtask, &task_id, flat_row, at, workflow)?;
                 }
-                _ => panic!("Expected a list: {:?}", task),
             }
         } else if obj.contains_key("select_label") {
-            let field: SelectField =
-                serde_json::from_value(task.clone()).expect("Invalid select field");
+            let field: SelectField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid select field: {}", e)))?;
 
-            let value: String = match field.value {
-                Some(v) => v,
-                None => String::from(""),
-            };
+            let value: String = field.value.unwrap_or_default();
             flat_row.insert(
                 get_key(&field.select_label, task, &task_id),
                 flat::FlatField::Select { value },
             );
         } else if obj.contains_key("task_label") {
-            let field: TextField =
-                serde_json::from_value(task.clone()).expect("Invalid text field");
+            let field: TextField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid text field: {}", e)))?;
 
-            let value: String = match field.value {
-                Some(v) => v,
-                None => String::from(""),
-            };
+            let value: String = field.value.unwrap_or_default();
             flat_row.insert(
                 get_key(&field.task_label, task, &task_id),
                 flat::FlatField::Text { value },
             );
         } else if obj.contains_key("tool_label") && obj.contains_key("width") {
-            let field: BoxField = serde_json::from_value(task.clone()).expect("Invalid box field");
+            let field: BoxField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid box field: {}", e)))?;
 
             flat_row.insert(
-                get_key(&field.tool_label, task, &task_id),
+                get_mark_key(&field.tool_label, task, &task_id, flat_row),
                 flat::FlatField::Box_ {
                     left: field.x.round() as i32,
                     top: field.y.round() as i32,
@@ -215,11 +722,11 @@ fn flatten_tasks(task: &Value, task_id: &str, flat_row: &mut flat::FlatRow) {
                 },
             );
         } else if obj.contains_key("tool_label") && obj.contains_key("x1") {
-            let field: LengthField =
-                serde_json::from_value(task.clone()).expect("Invalid length field");
+            let field: LengthField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid length field: {}", e)))?;
 
             flat_row.insert(
-                get_key(&field.tool_label, task, &task_id),
+                get_mark_key(&field.tool_label, task, &task_id, flat_row),
                 flat::FlatField::Length {
                     x1: field.x1.round() as i32,
                     y1: field.y1.round() as i32,
@@ -228,11 +735,11 @@ fn flatten_tasks(task: &Value, task_id: &str, flat_row: &mut flat::FlatRow) {
                 },
             );
         } else if obj.contains_key("tool_label") && obj.contains_key("x") {
-            let field: PointField =
-                serde_json::from_value(task.clone()).expect("Invalid point field");
+            let field: PointField = serde_json::from_value(task.clone())
+                .map_err(|e| invalid(format!("Invalid point field: {}", e)))?;
 
             flat_row.insert(
-                get_key(&field.tool_label, task, &task_id),
+                get_mark_key(&field.tool_label, task, &task_id, flat_row),
                 flat::FlatField::Point {
                     x: field.x.round() as i32,
                     y: field.y.round() as i32,
@@ -240,14 +747,170 @@ fn flatten_tasks(task: &Value, task_id: &str, flat_row: &mut flat::FlatRow) {
             );
         }
     } else {
-        panic!("Unkown field type in: {:?}", task)
+        return Err(invalid(format!("Unknown field type in: {}", task)));
+    };
+    Ok(())
+}
+
+// Flatten a task using its definition from the workflow CSV. Returns false if it's
+// a kind of task we don't know how to handle so the caller can fall back to guessing.
+fn flatten_workflow_task(
+    task: &Value,
+    spec: &workflow::Task,
+    task_id: &str,
+    flat_row: &mut flat::FlatRow,
+    at: &Location,
+    workflow: Option<&Workflow>,
+) -> Result<bool, FlattenError> {
+    let invalid = |message: String| FlattenError::Annotation {
+        at: at.clone(),
+        task: task_id.to_string(),
+        message,
     };
+
+    let value = &task["value"];
+    let values = || value.as_array().into_iter().flatten();
+
+    // Prefer the label in the annotation, the workflow's label may have been edited
+    let label = |value: &Value, fallback: &str| match value.as_str() {
+        Some(label) if !label.is_empty() => label.to_string(),
+        _ => fallback.to_string(),
+    };
+    let key = get_workflow_key(task_id, &label(&task["task_label"], &spec.label));
+
+    match &spec.kind {
+        TaskKind::Combo { .. } => {
+            for subtask in values() {
+                flatten_tasks(subtask, task_id, flat_row, at, workflow)?;
+            }
+        }
+        TaskKind::Text => {
+            let value = value.as_str().unwrap_or_default().to_string();
+            flat_row.insert(key, flat::FlatField::Text { value });
+        }
+        TaskKind::Single { answers } => {
+            let value = get_answer(value, answers);
+            flat_row.insert(key, flat::FlatField::Select { value });
+        }
+        TaskKind::Multiple { answers } => {
+            let mut values: Vec<String> = values().map(|v| get_answer(v, answers)).collect();
+            values.sort();
+            let joined = values.join(", ");
+            flat_row.insert(
+                key,
+                flat::FlatField::List {
+                    values,
+                    value: joined,
+                },
+            );
+        }
+        TaskKind::Dropdown { selects } => {
+            for (i, chosen) in values().enumerate() {
+                let select = selects.get(i);
+                let select_label = select.map_or("", |s| s.label.as_str());
+                let key = get_workflow_key(task_id, &label(&chosen["select_label"], select_label));
+
+                // Options picked from the list are stored by value, typed in ones aren't
+                let raw = match &chosen["value"] {
+                    Value::Null => "".to_string(),
+                    Value::String(raw) => raw.clone(),
+                    raw => raw.to_string(),
+                };
+                let value = select
+                    .and_then(|s| s.options.get(&raw))
+                    .cloned()
+                    .unwrap_or(raw);
+
+                flat_row.insert(key, flat::FlatField::Select { value });
+            }
+        }
+        TaskKind::Drawing { tools } => {
+            for mark in values() {
+                let tool = match mark["tool"].as_u64().and_then(|i| tools.get(i as usize)) {
+                    Some(tool) => tool,
+                    None => return Err(invalid(format!("Unknown drawing tool in: {}", mark))),
+                };
+                let tool_label = label(&mark["tool_label"], &tool.label);
+                let coord = |name: &str| {
+                    mark[name]
+                        .as_f64()
+                        .map(|v| v.round() as i32)
+                        .ok_or_else(|| {
+                            invalid(format!("Missing \"{}\" in the {} mark", name, tool_label))
+                        })
+                };
+
+                let field = match tool.kind {
+                    ToolKind::Box_ => {
+                        let (x, y) = (mark["x"].as_f64(), mark["y"].as_f64());
+                        let (width, height) = (mark["width"].as_f64(), mark["height"].as_f64());
+                        match (x, y, width, height) {
+                            (Some(x), Some(y), Some(width), Some(height)) => {
+                                flat::FlatField::Box_ {
+                                    left: x.round() as i32,
+                                    top: y.round() as i32,
+                                    right: (x + width).round() as i32,
+                                    bottom: (y + height).round() as i32,
+                                }
+                            }
+                            _ => return Err(invalid(format!("Invalid box field: {}", mark))),
+                        }
+                    }
+                    ToolKind::Length => flat::FlatField::Length {
+                        x1: coord("x1")?,
+                        y1: coord("y1")?,
+                        x2: coord("x2")?,
+                        y2: coord("y2")?,
+                    },
+                    ToolKind::Point => flat::FlatField::Point {
+                        x: coord("x")?,
+                        y: coord("y")?,
+                    },
+                    ToolKind::Other => continue,
+                };
+                flat_row.insert(get_mark_key(&tool_label, mark, task_id, flat_row), field);
+            }
+        }
+        TaskKind::Other => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Single and multiple choice answers are stored as indices into the answer list
+fn get_answer(value: &Value, answers: &[String]) -> String {
+    match value {
+        Value::Number(i) => i
+            .as_u64()
+            .and_then(|i| answers.get(i as usize))
+            .cloned()
+            .unwrap_or_else(|| i.to_string()),
+        Value::String(answer) => answer.clone(),
+        Value::Null => "".to_string(),
+        answer => answer.to_string(),
+    }
+}
+
+fn get_workflow_key(task_id: &str, label: &str) -> String {
+    if label.is_empty() {
+        task_id.to_string()
+    } else {
+        format!("{}: {}", task_id, label)
+    }
 }
 
 fn get_key(label: &str, task: &Value, task_id: &str) -> String {
     format!("{}: {}", &get_task_id(task, task_id), label)
 }
 
+// Volunteers may draw several marks with the same tool, so later ones get numbered
+fn get_mark_key(label: &str, task: &Value, task_id: &str, flat_row: &flat::FlatRow) -> String {
+    let base = get_key(label, task, task_id);
+    (1..)
+        .map(|n| flat::mark_key(&base, n))
+        .find(|key| !flat_row.contains_key(key))
+        .unwrap()
+}
+
 fn get_task_id(task: &Value, task_id: &str) -> String {
     match task {
         Value::Object(obj) if obj.contains_key("task") => {
@@ -256,34 +919,3 @@ fn get_task_id(task: &Value, task_id: &str) -> String {
         _ => task_id.to_string(),
     }
 }
-
-fn get_workflow_id(
-    workflow_id: &Option<String>,
-    classifications_csv: &Path,
-) -> Result<String, Box<dyn Error>> {
-    let id = match workflow_id {
-        Some(id) => Some(id.clone()),
-        None => {
-            let mut reader = csv::Reader::from_path(classifications_csv)
-                .expect("Could not read the classifications CSV file");
-            let mut ids: HashSet<String> = HashSet::new();
-            for deserialized_row in reader.deserialize() {
-                let raw_row: HashMap<String, String> = deserialized_row
-                    .expect("Could not parse a row in the classifications CSV file");
-                ids.insert(raw_row["workflow_id"].clone());
-            }
-            if ids.len() == 1 {
-                ids.iter().next().cloned()
-            } else {
-                panic!("More than 1 workflow in this file, you must provide a workflow ID.")
-            }
-        }
-    };
-    Ok(id.unwrap())
-}
-
-fn get_workflow_name(classifications_csv: &Path) -> Result<String, Box<dyn Error>> {
-    let mut reader = csv::Reader::from_path(classifications_csv).unwrap();
-    let row: HashMap<String, String> = reader.deserialize().next().unwrap().unwrap();
-    Ok(row["workflow_name"].clone())
-}
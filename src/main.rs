@@ -1,11 +1,23 @@
 pub mod flat;
 pub mod flatten;
+pub mod output;
 pub mod reconcile;
 pub mod reconciled;
+pub mod summary;
+pub mod workflow;
 
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use flat::{Flat, FlatField};
+use flatten::{FlattenOptions, WorkflowSummary, WorkflowVersion};
+use pluralizer::pluralize;
+use reconcile::{MarkStrategy, ReconcileOptions};
+use reconciled::Reconciled;
 use std::error::Error;
-use std::path::PathBuf;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use workflow::Workflows;
 
 #[derive(Parser)]
 #[clap(
@@ -15,45 +27,396 @@ use std::path::PathBuf;
             value which may be calulated from the classifications."
 )]
 struct Cli {
-    ///Read Zooniverse classifications from this CSV file
-    #[clap(value_parser, value_name = "FILE")]
-    classifications_csv: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///Flatten the classifications into one column per answer
+    #[clap(group(ArgGroup::new("output").required(true).multiple(true)))]
+    Flatten {
+        #[clap(flatten)]
+        input: InputArgs,
+
+        ///Write the flattened classifications to this CSV file
+        #[clap(short, long, value_parser, value_name = "FILE", group = "output")]
+        flattened_csv: Option<PathBuf>,
+
+        ///Write the flattened classifications to this JSON file, or JSON Lines if it ends in ".jsonl"
+        #[clap(long, value_parser, value_name = "FILE", group = "output")]
+        flattened_json: Option<PathBuf>,
+
+        ///Write the types of the flattened CSV columns to this file instead of next to the CSV
+        #[clap(long, value_parser, value_name = "FILE", requires = "flattened-csv")]
+        flattened_types_csv: Option<PathBuf>,
+    },
+
+    ///Reconcile the classifications to one row per subject
+    #[clap(group(ArgGroup::new("output").required(true).multiple(true)))]
+    Reconcile {
+        #[clap(flatten)]
+        input: InputArgs,
+
+        #[clap(flatten)]
+        reconcile: ReconcileArgs,
+
+        ///Write the reconciled classifications to this CSV file
+        #[clap(short, long, value_parser, value_name = "FILE", group = "output")]
+        reconciled_csv: Option<PathBuf>,
 
-    ///Write the flattened classifications to this CSV file
-    #[clap(short, long, value_parser, value_name = "FILE")]
-    flattened_csv: Option<PathBuf>,
+        ///Write the reconciled classifications with an explanation of each value to this CSV file
+        #[clap(short, long, value_parser, value_name = "FILE", group = "output")]
+        explanations_csv: Option<PathBuf>,
+    },
 
-    ///Write the reconciled classifications to this CSV file
-    #[clap(short, long, value_parser, value_name = "FILE")]
-    reconciled_csv: Option<PathBuf>,
+    ///Reconcile the classifications and write an HTML summary for reviewing them
+    Summary {
+        #[clap(flatten)]
+        input: InputArgs,
 
-    ///Write the summary of the reconciliation to this HTML file
-    #[clap(short, long, value_parser, value_name = "FILE")]
-    summary_html: Option<PathBuf>,
+        #[clap(flatten)]
+        reconcile: ReconcileArgs,
+
+        ///Write the summary of the reconciliation to this HTML file
+        #[clap(short, long, value_parser, value_name = "FILE")]
+        summary_html: PathBuf,
+    },
+
+    ///Print the counts and columns of the flattened classifications
+    Inspect {
+        #[clap(flatten)]
+        input: InputArgs,
+    },
+
+    ///List the workflows in a classifications export
+    Workflows {
+        ///Read Zooniverse classifications from this CSV file, use "-" for stdin
+        #[clap(value_parser, value_name = "FILE")]
+        classifications_csv: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct InputArgs {
+    ///Read Zooniverse classifications from this CSV file, use "-" for stdin
+    #[clap(value_parser, value_name = "FILE")]
+    classifications_csv: PathBuf,
 
     ///The workflow ID
     #[clap(short, long, value_parser, value_name = "ID")]
     workflow_id: Option<String>,
 
+    ///Skip classifications made with a workflow version older than this, like "12.3"
+    #[clap(long, value_parser, value_name = "VERSION")]
+    min_workflow_version: Option<WorkflowVersion>,
+
+    ///Skip classifications made with a workflow version newer than this, like "14.0"
+    #[clap(long, value_parser, value_name = "VERSION")]
+    max_workflow_version: Option<WorkflowVersion>,
+
     ///Read workflow strings from this CSV file
     #[clap(long, value_parser, value_name = "FILE")]
     workflow_csv: Option<PathBuf>,
+
+    ///Skip classification rows that cannot be parsed instead of stopping
+    #[clap(long, action, conflicts_with = "from-flattened")]
+    lenient: bool,
+
+    ///Write the rows skipped in lenient mode to this CSV file
+    #[clap(long, value_parser, value_name = "FILE")]
+    rejects_csv: Option<PathBuf>,
+
+    ///The input is a flattened CSV, JSON, or JSON Lines file written by this program, not Zooniverse classifications
+    #[clap(long, action)]
+    from_flattened: bool,
+
+    ///Read the types of the flattened CSV columns from this file instead of from next to it
+    #[clap(long, value_parser, value_name = "FILE", requires = "from-flattened")]
+    column_types_csv: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ReconcileArgs {
+    ///How to combine drawn boxes and points: mean, median, trimmed-mean, or cluster
+    #[clap(long, value_parser, value_name = "STRATEGY", default_value = "mean")]
+    mark_strategy: MarkStrategy,
+
+    ///Use a different mark strategy for one column, like "T4: Specimen=cluster"
+    #[clap(long, value_parser = parse_column_strategy, value_name = "COLUMN=STRATEGY")]
+    column_strategy: Vec<(String, MarkStrategy)>,
+}
+
+fn parse_column_strategy(arg: &str) -> Result<(String, MarkStrategy), String> {
+    match arg.rsplit_once('=') {
+        Some((column, strategy)) => Ok((column.trim().to_string(), strategy.trim().parse()?)),
+        None => Err(format!("Expected COLUMN=STRATEGY but got \"{}\"", arg)),
+    }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
     pluralizer::initialize();
 
     let args = Cli::parse();
 
-    let flat = flatten::flatten(&args.classifications_csv, &args.workflow_id)?;
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(args: Cli) -> Result<(), Box<dyn Error>> {
+    match args.command {
+        Command::Flatten {
+            input,
+            flattened_csv,
+            flattened_json,
+            flattened_types_csv,
+        } => {
+            let flat = read_input(&input)?;
+
+            // The column types go along with the CSV so it can be read back in
+            if let Option::Some(flat_csv) = flattened_csv {
+                flat.write_csv(&flat_csv)?;
+                let types_csv =
+                    flattened_types_csv.unwrap_or_else(|| flat::column_types_path(&flat_csv));
+                flat.write_column_types_csv(&types_csv)?;
+            }
+
+            if let Option::Some(flat_json) = flattened_json {
+                match flat_json.extension().and_then(|e| e.to_str()) {
+                    Some("jsonl") => flat.write_jsonl(&flat_json)?,
+                    _ => flat.write_json(&flat_json)?,
+                }
+            }
+            report_rejects(&flat);
+        }
+
+        Command::Reconcile {
+            input,
+            reconcile,
+            reconciled_csv,
+            explanations_csv,
+        } => {
+            let flat = read_input(&input)?;
+            let reconciled = reconcile_flat(&flat, reconcile);
+
+            if let Option::Some(reconciled_csv) = reconciled_csv {
+                reconciled.write_csv(&reconciled_csv)?;
+            }
+
+            if let Option::Some(explanations_csv) = explanations_csv {
+                reconciled.write_explanations_csv(&explanations_csv)?;
+            }
+            report_rejects(&flat);
+        }
+
+        Command::Summary {
+            input,
+            reconcile,
+            summary_html,
+        } => {
+            let flat = read_input(&input)?;
+            let reconciled = reconcile_flat(&flat, reconcile);
+            summary::write_html(&summary_html, &flat, &reconciled)?;
+            report_rejects(&flat);
+        }
+
+        Command::Inspect { input } => {
+            let flat = read_input(&input)?;
+            print(|out| inspect(out, &flat))?;
+        }
 
-    if let Option::Some(flat_csv) = args.flattened_csv {
-        _ = flat.write_csv(&flat_csv);
+        Command::Workflows {
+            classifications_csv,
+        } => {
+            let workflows = flatten::list_workflows(&classifications_csv)?;
+            print(|out| print_workflows(out, &workflows))?;
+        }
     }
 
-    // if let Option::Some(_reconciled_csv) = args.reconciled_csv {
-    //     let _rec = reconcile(&flattened);
-    // }
+    Ok(())
+}
+
+fn read_input(input: &InputArgs) -> Result<Flat, Box<dyn Error>> {
+    let workflows = match &input.workflow_csv {
+        Some(workflow_csv) => workflow::read_workflows(workflow_csv)?,
+        None => Workflows::default(),
+    };
+
+    let options = FlattenOptions {
+        workflow_id: input.workflow_id.clone(),
+        workflows,
+        min_version: input.min_workflow_version,
+        max_version: input.max_workflow_version,
+        lenient: input.lenient,
+    };
+
+    let flat = if input.from_flattened {
+        let mut flat = read_flattened(
+            &input.classifications_csv,
+            input.column_types_csv.as_deref(),
+            &options,
+        )?;
+        filter_versions(&mut flat, &options)?;
+        flat
+    } else {
+        flatten::flatten(&input.classifications_csv, &options)?
+    };
+
+    if let Option::Some(rejects_csv) = &input.rejects_csv {
+        flat.write_rejects_csv(rejects_csv)?;
+    }
+
+    Ok(flat)
+}
+
+fn reconcile_flat(flat: &Flat, args: ReconcileArgs) -> Reconciled {
+    let mut reconciled = Reconciled::new(&flat.workflow_id, &flat.workflow_name);
+    let options = ReconcileOptions {
+        mark_strategy: args.mark_strategy,
+        column_strategies: args.column_strategy.into_iter().collect(),
+    };
+    reconciled.reconcile(flat, &options);
+    reconciled
+}
+
+// Stop quietly when whatever reads the output goes away early, like head does
+fn print<F>(write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut io::StdoutLock) -> io::Result<()>,
+{
+    let mut out = io::stdout().lock();
+    match write(&mut out).and_then(|_| out.flush()) {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+fn inspect(out: &mut impl Write, flat: &Flat) -> io::Result<()> {
+    writeln!(out, "Workflow ID:     {}", flat.workflow_id)?;
+    writeln!(out, "Workflow name:   {}", flat.workflow_name)?;
+    writeln!(out, "Classifications: {}", flat.classification_count())?;
+    writeln!(out, "Flattened rows:  {}", flat.row_count())?;
+    writeln!(out, "Subjects:        {}", flat.subject_count())?;
+    writeln!(out, "Rejected rows:   {}", flat.rejects().len())?;
+    writeln!(out, "Columns:")?;
+    for (column, field_type) in flat.columns() {
+        writeln!(out, "    {:<8} {}", field_type.type_name(), column)?;
+    }
+    Ok(())
+}
+
+// Tab separated so it lines up in a terminal and is easy to cut apart in scripts
+fn print_workflows(out: &mut impl Write, workflows: &[WorkflowSummary]) -> io::Result<()> {
+    writeln!(
+        out,
+        "workflow_id\tworkflow_name\tversions\tclassifications\tsubjects\tfirst\tlast"
+    )?;
+    for workflow in workflows {
+        let versions: Vec<String> = workflow.versions.iter().map(|v| v.to_string()).collect();
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            workflow.workflow_id,
+            workflow.workflow_name,
+            versions.join(", "),
+            workflow.classification_count,
+            workflow.subject_count,
+            workflow.first_created,
+            workflow.last_created
+        )?;
+    }
+    Ok(())
+}
+
+fn report_rejects(flat: &Flat) {
+    if !flat.rejects().is_empty() {
+        eprintln!(
+            "Skipped {} that could not be flattened",
+            pluralize("bad row", flat.rejects().len() as isize, true)
+        );
+    }
+}
+
+// Flattened CSV and JSON Lines files don't say which workflow they came from, so
+// use the workflow ID and the workflow CSV if they were given
+fn read_flattened(
+    path: &Path,
+    types_csv: Option<&Path>,
+    options: &FlattenOptions,
+) -> Result<Flat, Box<dyn Error>> {
+    let workflow = options
+        .workflow_id
+        .as_ref()
+        .and_then(|id| options.workflows.latest(id));
+
+    let mut flat = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => return Flat::read_json(path),
+        Some("jsonl") => Flat::read_jsonl(path, workflow)?,
+        _ => read_flattened_csv(path, types_csv)?,
+    };
+
+    if let Some(workflow_id) = &options.workflow_id {
+        flat.workflow_id = workflow_id.clone();
+        if let Some(workflow) = workflow {
+            flat.workflow_name = workflow.workflow_name.clone();
+        }
+    }
+    Ok(flat)
+}
+
+// Without the column types written with the CSV we have to guess them, and a
+// guessed select or list column is reconciled as text, so say so
+fn read_flattened_csv(path: &Path, types_csv: Option<&Path>) -> Result<Flat, Box<dyn Error>> {
+    let types_csv = match types_csv {
+        Some(types_csv) => Some(types_csv.to_path_buf()),
+        None => Some(flat::column_types_path(path)).filter(|p| p.exists()),
+    };
+
+    let flat = Flat::read_csv(path, types_csv.as_deref())?;
+
+    if types_csv.is_none() {
+        let guessed: Vec<&str> = flat
+            .columns()
+            .iter()
+            .filter(|(c, f)| flat::is_task_column(c) && matches!(f, FlatField::Text { .. }))
+            .map(|(c, _)| c.as_str())
+            .collect();
+        if !guessed.is_empty() {
+            eprintln!(
+                "Warning: no column types for {}, reconciling these as text: {}",
+                path.display(),
+                guessed.join(", ")
+            );
+        }
+    }
+    Ok(flat)
+}
+
+// Apply the workflow version range to rows that were flattened before
+fn filter_versions(flat: &mut Flat, options: &FlattenOptions) -> Result<(), Box<dyn Error>> {
+    if options.min_version.is_none() && options.max_version.is_none() {
+        return Ok(());
+    }
+
+    let mut keep: Vec<bool> = Vec::new();
+    for row in flat.rows() {
+        let raw_version = match row.get(flatten::WORKFLOW_VER) {
+            Some(FlatField::NoOp { value }) => value.as_str(),
+            _ => "",
+        };
+        let version: WorkflowVersion = raw_version.parse().map_err(|e| {
+            let classification_id = match row.get(flatten::CLASSIFICATION_ID) {
+                Some(FlatField::NoOp { value }) => value.as_str(),
+                _ => "",
+            };
+            format!("{} for classification \"{}\"", e, classification_id)
+        })?;
+        keep.push(flatten::version_in_range(version, options));
+    }
 
+    let mut keep = keep.into_iter();
+    flat.retain_rows(|_| keep.next().unwrap_or(false));
     Ok(())
 }
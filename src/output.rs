@@ -0,0 +1,38 @@
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+pub type Output = BufWriter<File>;
+
+// Write a file by way of a temporary file in the same directory that's renamed
+// into place when everything is written, so a failed write never leaves a partial
+// file behind that looks complete. Errors mention the file we were writing.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut Output) -> Result<(), Box<dyn Error>>,
+{
+    let temp_path = temp_path(path);
+
+    let written = File::create(&temp_path)
+        .map_err(|e| e.into())
+        .and_then(|file| {
+            let mut output = BufWriter::new(file);
+            write(&mut output)?;
+            let file = output.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&temp_path, path).map_err(|e| e.into()));
+
+    written.map_err(|err: Box<dyn Error>| {
+        _ = fs::remove_file(&temp_path);
+        format!("Could not write {}: {}", path.display(), err).into()
+    })
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
@@ -1,215 +1,1027 @@
-// use crate::flat::FlatField;
-// use crate::reconcile::{ReconciledCell, ReconciledField, ReconciledFlag};
-// use lazy_static::lazy_static;
-// use pluralizer::pluralize;
-// use regex::Regex;
-// use std::collections::BTreeSet;
-//
-// pub fn reconcile_boxes(fields: Vec<&FlatField>) -> ReconciledCell {
-//     let mut sums = (0.0, 0.0, 0.0, 0.0); // Temp buffer for calculations
-//     let mut notes = "There are no box records".to_string();
-//     let mut flag = ReconciledFlag::Empty;
-//     let mut count = 0;
-//
-//     // Accumulate the box edges
-//     fields.iter().for_each(|field| {
-//         if let FlatField::Box_ {
-//             left,
-//             top,
-//             right,
-//             bottom,
-//         } = field
-//         {
-//             count += 1;
-//             sums.0 += left;
-//             sums.1 += top;
-//             sums.2 += right;
-//             sums.3 += bottom;
-//         };
-//     });
-//
-//     // If there are boxes
-//     if count > 0 {
-//         let len = fields.len() as f32;
-//         sums.0 /= len;
-//         sums.1 /= len;
-//         sums.2 /= len;
-//         sums.3 /= len;
-//
-//         flag = ReconciledFlag::Ok;
-//
-//         notes = format!(
-//             "There {} {} box {}",
-//             pluralize("is", count, false),
-//             count,
-//             pluralize("record", count, false)
-//         );
-//     }
-//
-//     ReconciledCell {
-//         flag,
-//         notes,
-//         field: ReconciledField::Box_ {
-//             left: sums.0.round(),
-//             top: sums.1.round(),
-//             right: sums.2.round(),
-//             bottom: sums.3.round(),
-//         },
-//     }
-// }
-//
-// pub fn reconcile_lengths(fields: Vec<&FlatField>, header: &str) -> ReconciledCell {
-//     let mut pixel_length: f32 = 0.0;
-//     let mut factor: f32 = 0.0;
-//     let mut units: String = "".to_string();
-//     let mut is_scale: bool = false;
-//     let mut notes = "There are no length records".to_string();
-//     let mut flag = ReconciledFlag::Empty;
-//     let mut count = 0;
-//
-//     lazy_static! {
-//         static ref SCALE_RE: Regex =
-//             Regex::new(r"(?x) (?P<scale> [0-9.]+ ) \s* (?P<units> (mm|cm|dm|m) ) \b").unwrap();
-//     }
-//
-//     // Accumulate the lengths
-//     fields.iter().for_each(|field| {
-//         if let FlatField::Length { x1, y1, x2, y2 } = field {
-//             count += 1;
-//             pixel_length += ((x1 - x2) * (x1 - x2) + (y1 - y2) * (y1 - y2)).sqrt();
-//         };
-//     });
-//
-//     // We have valid lengths
-//     if count > 0 {
-//         pixel_length /= fields.len() as f32;
-//         flag = ReconciledFlag::Ok;
-//
-//         notes = format!(
-//             "There {} {} length {}",
-//             pluralize("is", count, false),
-//             count,
-//             pluralize("record", count, false)
-//         );
-//     }
-//
-//     // Is this a scale bar or a measurement
-//     if let Some(groups) = SCALE_RE.captures(header) {
-//         units = groups["units"].to_string();
-//         factor = groups["scale"].parse::<f32>().unwrap() / pixel_length;
-//         is_scale = true;
-//     }
-//
-//     let field = if is_scale {
-//         ReconciledField::RulerLength {
-//             length: 0.0,
-//             pixel_length,
-//             factor,
-//             units,
-//         }
-//     } else {
-//         ReconciledField::Length {
-//             length: 0.0,
-//             pixel_length,
-//             units,
-//         }
-//     };
-//
-//     ReconciledCell { flag, notes, field }
-// }
-//
-// pub fn reconcile_points(fields: Vec<&FlatField>) -> ReconciledCell {
-//     let mut sums = (0.0, 0.0); // Temp buffer for calculations
-//     let mut notes = "There are no point records".to_string();
-//     let mut flag = ReconciledFlag::Empty;
-//     let mut count = 0;
-//
-//     // Accumulate the point coordinates
-//     fields.iter().for_each(|field| {
-//         if let FlatField::Point { x, y } = field {
-//             count += 1;
-//             sums.0 += x;
-//             sums.1 += y;
-//         };
-//     });
-//
-//     // If there are points
-//     if count > 0 {
-//         let len = fields.len() as f32;
-//         sums.0 /= len;
-//         sums.1 /= len;
-//
-//         flag = ReconciledFlag::Ok;
-//
-//         notes = format!(
-//             "There {} {} point {}",
-//             pluralize("is", count, false),
-//             count,
-//             pluralize("record", count, false)
-//         );
-//     }
-//
-//     ReconciledCell {
-//         flag,
-//         notes,
-//         field: ReconciledField::Point {
-//             x: sums.0.round(),
-//             y: sums.1.round(),
-//         },
-//     }
-// }
-//
-// pub fn reconcile_same(fields: Vec<&FlatField>) -> ReconciledCell {
-//     let mut notes = "".to_string();
-//     let mut flag = ReconciledFlag::Ok;
-//     let mut values: BTreeSet<&String> = BTreeSet::new();
-//     let mut value = "".to_string();
-//
-//     fields.iter().for_each(|field| {
-//         if let FlatField::Same { value } = field {
-//             values.insert(value);
-//         }
-//     });
-//
-//     if values.len() == 0 {
-//         flag = ReconciledFlag::Empty;
-//         notes = "There are no records".to_string();
-//     } else if values.len() > 1 {
-//         flag = ReconciledFlag::Error;
-//         let joined: String = values
-//             .iter()
-//             .map(|v| v.to_string())
-//             .collect::<Vec<String>>()
-//             .join(", ");
-//         notes = format!("Not all values are the same: {}", joined);
-//     } else {
-//         let first: Vec<String> = values.iter().take(1).map(|v| v.to_string()).collect();
-//         value = first[0].to_string();
-//     }
-//
-//     ReconciledCell {
-//         flag,
-//         notes,
-//         field: ReconciledField::Same { value },
-//     }
-// }
-//
-// pub fn reconcile_select(fields: Vec<&FlatField>) -> ReconciledCell {
-//     let mut notes = "".to_string();
-//     let mut flag = ReconciledFlag::Ok;
-//     let mut values: BTreeSet<&String> = BTreeSet::new();
-//
-//     // Nobody chose a value
-//     // Top values are tied
-//     // We have a winner
-//     // Only one person chose a value
-//     // Everyone chose a different value
-//
-//     ReconciledCell {
-//         flag,
-//         notes,
-//         field: ReconciledField::Select {
-//             value: "".to_string(),
-//         },
-//     }
-// }
+use crate::flat::FlatField;
+use crate::reconciled::{ReconciledField, ReconciledFlag, ReconciledResult, ReconciledRow};
+use fuzzywuzzy::fuzz;
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
+use pluralizer::pluralize;
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+// Fuzzy scores below this are not considered a match
+const FUZZY_CUTOFF: u8 = 90;
+
+// Boxes that overlap less than this are not in the same cluster
+const IOU_CUTOFF: f32 = 0.5;
+
+// Points farther apart than this many pixels are not in the same cluster
+const DISTANCE_CUTOFF: f32 = 50.0;
+
+// Fraction of marks dropped from each end by the trimmed mean, so twice this
+// fraction of the marks farthest from the median mark are dropped
+const TRIM_FRACTION: f32 = 0.2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MarkStrategy {
+    Mean,
+    Median,
+    TrimmedMean,
+    Cluster,
+}
+
+impl FromStr for MarkStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(MarkStrategy::Mean),
+            "median" => Ok(MarkStrategy::Median),
+            "trimmed-mean" => Ok(MarkStrategy::TrimmedMean),
+            "cluster" => Ok(MarkStrategy::Cluster),
+            _ => Err(format!(
+                "Unknown mark strategy \"{}\", use mean, median, trimmed-mean, or cluster",
+                s
+            )),
+        }
+    }
+}
+
+// How to reconcile drawn marks. A column listed in `column_strategies` overrides
+// the default `mark_strategy`.
+#[derive(Debug)]
+pub struct ReconcileOptions {
+    pub mark_strategy: MarkStrategy,
+    pub column_strategies: HashMap<String, MarkStrategy>,
+}
+
+impl Default for ReconcileOptions {
+    fn default() -> Self {
+        ReconcileOptions {
+            mark_strategy: MarkStrategy::Mean,
+            column_strategies: HashMap::new(),
+        }
+    }
+}
+
+impl ReconcileOptions {
+    pub fn strategy(&self, column: &str) -> MarkStrategy {
+        *self
+            .column_strategies
+            .get(column)
+            .unwrap_or(&self.mark_strategy)
+    }
+}
+
+pub fn reconcile_same(fields: Vec<&FlatField>) -> ReconciledField {
+    let mut notes = "".to_string();
+    let mut flag = ReconciledFlag::Ok;
+    let mut values: BTreeSet<&String> = BTreeSet::new();
+    let mut value = "".to_string();
+
+    fields.iter().for_each(|field| {
+        if let FlatField::Same { value } = field {
+            values.insert(value);
+        }
+    });
+
+    if values.is_empty() {
+        flag = ReconciledFlag::Empty;
+        notes = "There are no records".to_string();
+    } else if values.len() > 1 {
+        flag = ReconciledFlag::Error;
+        let joined: String = values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        notes = format!("Not all values are the same: {}", joined);
+    } else {
+        let first: Vec<String> = values.iter().take(1).map(|v| v.to_string()).collect();
+        value = first[0].to_string();
+    }
+
+    ReconciledField::Same {
+        value,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+pub fn reconcile_select(fields: Vec<&FlatField>) -> ReconciledField {
+    let notes: String;
+    let flag: ReconciledFlag;
+    let mut value = "".to_string();
+
+    let values: Vec<&str> = fields
+        .iter()
+        .filter_map(|field| match field {
+            FlatField::Select { value } => Some(value.trim()),
+            _ => None,
+        })
+        .collect();
+
+    let total = values.len();
+    let filled: Vec<&str> = values.into_iter().filter(|v| !v.is_empty()).collect();
+    let blanks = blanks_note(total - filled.len());
+    let counts = count_values(&filled);
+
+    if total == 0 {
+        // There are no records
+        flag = ReconciledFlag::Empty;
+        notes = "There are no records".to_string();
+    } else if filled.is_empty() {
+        // Nobody chose a value
+        flag = ReconciledFlag::AllBlank;
+        notes = format!(
+            "All {} are blank",
+            pluralize("record", total as isize, true)
+        );
+    } else if filled.len() == 1 {
+        // Only one person chose a value
+        flag = ReconciledFlag::OnlyOne;
+        value = filled[0].to_string();
+        notes = format!(
+            "Only 1 transcriber in {}{}",
+            pluralize("record", total as isize, true),
+            blanks
+        );
+    } else if counts[0].1 == 1 {
+        // Everyone chose a different value
+        flag = ReconciledFlag::NoMatch;
+        notes = format!(
+            "No select match on {}{}",
+            pluralize("record", total as isize, true),
+            blanks
+        );
+    } else if counts.len() > 1 && counts[0].1 == counts[1].1 {
+        // Top values are tied
+        let tied = counts.iter().filter(|(_, c)| *c == counts[0].1).count();
+        flag = ReconciledFlag::Majority;
+        value = counts[0].0.to_string();
+        notes = format!(
+            "Match is a tie between the top {} on {}{}",
+            tied,
+            pluralize("record", total as isize, true),
+            blanks
+        );
+    } else if counts[0].1 == total {
+        // Everyone chose the same value
+        flag = ReconciledFlag::Unanimous;
+        value = counts[0].0.to_string();
+        notes = format!("Unanimous match, {} of {} records", total, total);
+    } else {
+        // We have a winner
+        flag = ReconciledFlag::Majority;
+        value = counts[0].0.to_string();
+        notes = format!(
+            "{} of {} matched{}",
+            counts[0].1,
+            pluralize("record", total as isize, true),
+            blanks
+        );
+    }
+
+    ReconciledField::Select {
+        value,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+pub fn reconcile_text(fields: Vec<&FlatField>) -> ReconciledField {
+    let notes: String;
+    let flag: ReconciledFlag;
+    let mut value = "".to_string();
+
+    // Collapse runs of whitespace so spacing differences don't block a match
+    let values: Vec<String> = fields
+        .iter()
+        .filter_map(|field| match field {
+            FlatField::Text { value } => {
+                Some(value.split_whitespace().collect::<Vec<&str>>().join(" "))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let total = values.len();
+    let filled: Vec<&str> = values
+        .iter()
+        .map(|v| v.as_str())
+        .filter(|v| !v.is_empty())
+        .collect();
+    let blanks = blanks_note(total - filled.len());
+
+    // Exact matches ignore case, but we report the first-seen spelling of the winner
+    let lowered: Vec<String> = filled.iter().map(|v| v.to_lowercase()).collect();
+    let keys: Vec<&str> = lowered.iter().map(|v| v.as_str()).collect();
+    let counts = count_values(&keys);
+
+    if total == 0 {
+        flag = ReconciledFlag::Empty;
+        notes = "There are no records".to_string();
+    } else if filled.is_empty() {
+        flag = ReconciledFlag::AllBlank;
+        notes = format!(
+            "All {} are blank",
+            pluralize("record", total as isize, true)
+        );
+    } else if filled.len() == 1 {
+        flag = ReconciledFlag::OnlyOne;
+        value = filled[0].to_string();
+        notes = format!(
+            "Only 1 transcriber in {}{}",
+            pluralize("record", total as isize, true),
+            blanks
+        );
+    } else if counts[0].1 > 1 && (counts.len() == 1 || counts[0].1 > counts[1].1) {
+        let first = keys.iter().position(|k| *k == counts[0].0).unwrap();
+        value = filled[first].to_string();
+        if counts[0].1 == total {
+            flag = ReconciledFlag::Unanimous;
+            notes = format!("Unanimous match, {} of {} records", total, total);
+        } else {
+            flag = ReconciledFlag::Majority;
+            notes = format!(
+                "{} of {} matched{}",
+                counts[0].1,
+                pluralize("record", total as isize, true),
+                blanks
+            );
+        }
+    } else {
+        match best_fuzzy_match(&filled) {
+            Some((best, score)) => {
+                flag = ReconciledFlag::Fuzzy;
+                value = best.to_string();
+                notes = format!(
+                    "Fuzzy match on {} with score {}{}",
+                    pluralize("record", total as isize, true),
+                    score,
+                    blanks
+                );
+            }
+            None => {
+                flag = ReconciledFlag::NoMatch;
+                notes = format!(
+                    "No text match on {}{}",
+                    pluralize("record", total as isize, true),
+                    blanks
+                );
+            }
+        }
+    }
+
+    ReconciledField::Text {
+        value,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+pub fn reconcile_list(fields: Vec<&FlatField>, row_count: usize) -> ReconciledField {
+    let notes: String;
+    let flag: ReconciledFlag;
+
+    // Every option is a separate vote, so count how many volunteers checked each one
+    let mut counts: IndexMap<&str, usize> = IndexMap::new();
+    fields.iter().for_each(|field| {
+        if let FlatField::List { values, .. } = field {
+            let chosen: BTreeSet<&String> = values.iter().collect();
+            for option in chosen {
+                *counts.entry(option.as_str()).or_insert(0) += 1;
+            }
+        }
+    });
+    counts.sort_by(|a_key, a_count, b_key, b_count| b_count.cmp(a_count).then(a_key.cmp(b_key)));
+
+    let mut values: Vec<String> = counts
+        .iter()
+        .filter(|(_, &count)| count * 2 > row_count)
+        .map(|(option, _)| option.to_string())
+        .collect();
+    values.sort();
+    let value = values.join(", ");
+
+    let tally = counts
+        .iter()
+        .map(|(option, count)| format!("{}: {} of {}", option, count, row_count))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    if row_count == 0 {
+        flag = ReconciledFlag::Empty;
+        notes = "There are no records".to_string();
+    } else if counts.is_empty() {
+        flag = ReconciledFlag::AllBlank;
+        notes = format!(
+            "No options chosen in {}",
+            pluralize("record", row_count as isize, true)
+        );
+    } else if values.is_empty() {
+        flag = ReconciledFlag::NoMatch;
+        notes = format!("No option chosen by a majority ({})", tally);
+    } else if counts.values().all(|&count| count == row_count) {
+        flag = ReconciledFlag::Unanimous;
+        notes = format!("Unanimous match ({})", tally);
+    } else {
+        flag = ReconciledFlag::Majority;
+        notes = format!("Majority match ({})", tally);
+    }
+
+    ReconciledField::List {
+        values,
+        value,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+// Pick the transcription that agrees best with the others. Every value is scored
+// against every other value with both a plain and a token set ratio, and the winner
+// is the value with the best single score, then the most total support, then the
+// longest text.
+fn best_fuzzy_match<'a>(values: &[&'a str]) -> Option<(&'a str, u8)> {
+    // The fuzzywuzzy crate slices strings by byte so it panics on multi-byte characters
+    let keys: Vec<String> = values
+        .iter()
+        .map(|v| v.to_lowercase().chars().filter(char::is_ascii).collect())
+        .collect();
+
+    let mut best: Option<(&str, u8, u32)> = None;
+
+    for (i, key) in keys.iter().enumerate() {
+        let mut top: u8 = 0;
+        let mut support: u32 = 0;
+
+        for (j, other) in keys.iter().enumerate() {
+            if i == j || key.is_empty() || other.is_empty() {
+                continue;
+            }
+            let score = fuzz::ratio(key, other).max(fuzz::token_set_ratio(key, other, true, true));
+            top = top.max(score);
+            support += score as u32;
+        }
+
+        let candidate = (values[i], top, support);
+        best = match best {
+            Some(current) if rank(&current) >= rank(&candidate) => Some(current),
+            _ => Some(candidate),
+        };
+    }
+
+    match best {
+        Some((value, score, _)) if score >= FUZZY_CUTOFF => Some((value, score)),
+        _ => None,
+    }
+}
+
+fn rank(candidate: &(&str, u8, u32)) -> (u8, u32, usize) {
+    (candidate.1, candidate.2, candidate.0.len())
+}
+
+// Count each distinct value, most common first. Ties keep their first-seen order.
+fn count_values<'a>(values: &[&'a str]) -> Vec<(&'a str, usize)> {
+    let mut counts: IndexMap<&str, usize> = IndexMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    counts
+}
+
+fn blanks_note(blanks: usize) -> String {
+    if blanks == 0 {
+        "".to_string()
+    } else {
+        format!(", {} blank", pluralize("record", blanks as isize, true))
+    }
+}
+
+pub fn reconcile_boxes(fields: Vec<&FlatField>, strategy: MarkStrategy) -> ReconciledField {
+    let mut edges = [0.0, 0.0, 0.0, 0.0]; // Temp buffer for calculations
+    let mut notes = "There are no box records".to_string();
+    let mut flag = ReconciledFlag::Empty;
+
+    // Gather the box edges
+    let marks: Vec<[f32; 4]> = fields
+        .iter()
+        .filter_map(|field| match field {
+            FlatField::Box_ {
+                left,
+                top,
+                right,
+                bottom,
+            } => Some([*left as f32, *top as f32, *right as f32, *bottom as f32]),
+            _ => None,
+        })
+        .collect();
+    let count = marks.len() as isize;
+
+    // If there are boxes, combine only the ones people actually drew
+    if count > 0 {
+        let discarded;
+        (edges, discarded) = summarize(&marks, strategy, |a, b| iou(a, b) >= IOU_CUTOFF);
+
+        flag = ReconciledFlag::Ok;
+
+        notes = format!(
+            "There {} {} box {}{}",
+            pluralize("is", count, false),
+            count,
+            pluralize("record", count, false),
+            outliers_note(discarded)
+        );
+    }
+
+    ReconciledField::Box_ {
+        left: edges[0].round() as i32,
+        top: edges[1].round() as i32,
+        right: edges[2].round() as i32,
+        bottom: edges[3].round() as i32,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+pub fn reconcile_lengths(fields: Vec<&FlatField>, header: &str) -> ReconciledField {
+    let mut sums = (0.0, 0.0, 0.0, 0.0); // Temp buffer for calculations
+    let mut pixel_length: f32 = 0.0;
+    let mut factor: f32 = 0.0;
+    let mut units: String = "".to_string();
+    let mut is_scale: bool = false;
+    let mut notes = "There are no length records".to_string();
+    let mut flag = ReconciledFlag::Empty;
+    let mut count = 0;
+
+    lazy_static! {
+        static ref SCALE_RE: Regex =
+            Regex::new(r"(?x) (?P<scale> [0-9.]+ ) \s* (?P<units> (mm|cm|dm|m) ) \b").unwrap();
+    }
+
+    // Accumulate the lengths
+    fields.iter().for_each(|field| {
+        if let FlatField::Length { x1, y1, x2, y2 } = field {
+            count += 1;
+            sums.0 += *x1 as f32;
+            sums.1 += *y1 as f32;
+            sums.2 += *x2 as f32;
+            sums.3 += *y2 as f32;
+            let (dx, dy) = ((x1 - x2) as f32, (y1 - y2) as f32);
+            pixel_length += (dx * dx + dy * dy).sqrt();
+        };
+    });
+
+    // We have valid lengths, average only over the people who drew one
+    if count > 0 {
+        let len = count as f32;
+        sums.0 /= len;
+        sums.1 /= len;
+        sums.2 /= len;
+        sums.3 /= len;
+        pixel_length /= len;
+
+        flag = ReconciledFlag::Ok;
+
+        notes = format!(
+            "There {} {} length {}",
+            pluralize("is", count, false),
+            count,
+            pluralize("record", count, false)
+        );
+    }
+
+    // Is this a scale bar or a measurement
+    if let Some(groups) = SCALE_RE.captures(header) {
+        units = groups["units"].to_string();
+        if let Ok(scale) = groups["scale"].parse::<f32>() {
+            if pixel_length > 0.0 {
+                factor = scale / pixel_length;
+            }
+        }
+        is_scale = true;
+    }
+
+    let (x1, y1, x2, y2) = (
+        sums.0.round() as i32,
+        sums.1.round() as i32,
+        sums.2.round() as i32,
+        sums.3.round() as i32,
+    );
+    let result = ReconciledResult { flag, notes };
+
+    if is_scale {
+        ReconciledField::RulerLength {
+            x1,
+            y1,
+            x2,
+            y2,
+            length: pixel_length * factor,
+            pixel_length,
+            factor,
+            units,
+            result,
+        }
+    } else {
+        ReconciledField::Length {
+            x1,
+            y1,
+            x2,
+            y2,
+            length: 0.0,
+            pixel_length,
+            units,
+            result,
+        }
+    }
+}
+
+// Use the subject's scale bar to convert every other length into real units. If
+// volunteers measured more than one ruler we use the first one that has a factor.
+pub fn convert_lengths(row: &mut ReconciledRow) {
+    let ruler = row.iter().find_map(|(header, field)| match field {
+        ReconciledField::RulerLength { factor, units, .. } if *factor > 0.0 => {
+            Some((header.clone(), *factor, units.clone()))
+        }
+        _ => None,
+    });
+
+    if let Some((ruler_header, ruler_factor, ruler_units)) = ruler {
+        for field in row.values_mut() {
+            if let ReconciledField::Length {
+                length,
+                pixel_length,
+                units,
+                result,
+                ..
+            } = field
+            {
+                if result.flag == ReconciledFlag::Empty {
+                    continue;
+                }
+                *length = *pixel_length * ruler_factor;
+                *units = ruler_units.clone();
+                result.notes = format!(
+                    "{}, converted to {} using the \"{}\" ruler",
+                    result.notes, ruler_units, ruler_header
+                );
+            }
+        }
+    }
+}
+
+pub fn reconcile_points(fields: Vec<&FlatField>, strategy: MarkStrategy) -> ReconciledField {
+    let mut coords = [0.0, 0.0]; // Temp buffer for calculations
+    let mut notes = "There are no point records".to_string();
+    let mut flag = ReconciledFlag::Empty;
+
+    // Gather the point coordinates
+    let marks: Vec<[f32; 2]> = fields
+        .iter()
+        .filter_map(|field| match field {
+            FlatField::Point { x, y } => Some([*x as f32, *y as f32]),
+            _ => None,
+        })
+        .collect();
+    let count = marks.len() as isize;
+
+    // If there are points, combine only the ones people actually placed
+    if count > 0 {
+        let discarded;
+        (coords, discarded) = summarize(&marks, strategy, |a, b| distance(a, b) <= DISTANCE_CUTOFF);
+
+        flag = ReconciledFlag::Ok;
+
+        notes = format!(
+            "There {} {} point {}{}",
+            pluralize("is", count, false),
+            count,
+            pluralize("record", count, false),
+            outliers_note(discarded)
+        );
+    }
+
+    ReconciledField::Point {
+        x: coords[0].round() as i32,
+        y: coords[1].round() as i32,
+        result: ReconciledResult { flag, notes },
+    }
+}
+
+// Split marks drawn with the same tool into the separate objects volunteers were
+// marking, largest group first. Each group can then be reconciled on its own.
+pub fn cluster_marks(fields: Vec<&FlatField>) -> Vec<Vec<&FlatField>> {
+    cluster(&fields, |a, b| marks_close(a, b))
+        .into_iter()
+        .map(|members| members.into_iter().map(|i| fields[i]).collect())
+        .collect()
+}
+
+fn marks_close(a: &FlatField, b: &FlatField) -> bool {
+    match (a, b) {
+        (
+            FlatField::Box_ {
+                left: l1,
+                top: t1,
+                right: r1,
+                bottom: b1,
+            },
+            FlatField::Box_ {
+                left: l2,
+                top: t2,
+                right: r2,
+                bottom: b2,
+            },
+        ) => {
+            let a = [*l1 as f32, *t1 as f32, *r1 as f32, *b1 as f32];
+            let b = [*l2 as f32, *t2 as f32, *r2 as f32, *b2 as f32];
+            iou(&a, &b) >= IOU_CUTOFF
+        }
+        (
+            FlatField::Length {
+                x1: ax1,
+                y1: ay1,
+                x2: ax2,
+                y2: ay2,
+            },
+            FlatField::Length {
+                x1: bx1,
+                y1: by1,
+                x2: bx2,
+                y2: by2,
+            },
+        ) => {
+            let start = distance(&[*ax1 as f32, *ay1 as f32], &[*bx1 as f32, *by1 as f32]);
+            let end = distance(&[*ax2 as f32, *ay2 as f32], &[*bx2 as f32, *by2 as f32]);
+            start <= DISTANCE_CUTOFF && end <= DISTANCE_CUTOFF
+        }
+        (FlatField::Point { x: ax, y: ay }, FlatField::Point { x: bx, y: by }) => {
+            distance(&[*ax as f32, *ay as f32], &[*bx as f32, *by as f32]) <= DISTANCE_CUTOFF
+        }
+        _ => false,
+    }
+}
+
+// Combine drawn marks into a single mark. Returns the mark and how many of the
+// inputs were thrown out as outliers. The `close` test decides whether two marks
+// belong to the same cluster.
+fn summarize<const N: usize>(
+    marks: &[[f32; N]],
+    strategy: MarkStrategy,
+    close: impl Fn(&[f32; N], &[f32; N]) -> bool,
+) -> ([f32; N], usize) {
+    match strategy {
+        MarkStrategy::Mean => (mean(marks.iter()), 0),
+        MarkStrategy::Median => (median(marks), 0),
+        MarkStrategy::TrimmedMean => {
+            // Drop whole marks, the ones farthest from the median mark
+            let trim = (marks.len() as f32 * TRIM_FRACTION).floor() as usize;
+            let middle = median(marks);
+            let mut order: Vec<(f32, usize)> = marks
+                .iter()
+                .enumerate()
+                .map(|(i, mark)| {
+                    let dist: f32 = mark.iter().zip(middle).map(|(a, b)| (a - b).powi(2)).sum();
+                    (dist, i)
+                })
+                .collect();
+            order.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+            let kept = order.len() - trim * 2;
+            let combined = mean(order[..kept].iter().map(|&(_, i)| &marks[i]));
+            (combined, trim * 2)
+        }
+        MarkStrategy::Cluster => {
+            let clusters = cluster(marks, close);
+            let largest = &clusters[0];
+            let combined = mean(largest.iter().map(|&i| &marks[i]));
+            (combined, marks.len() - largest.len())
+        }
+    }
+}
+
+// The median of each coordinate separately
+fn median<const N: usize>(marks: &[[f32; N]]) -> [f32; N] {
+    let mut middle = [0.0; N];
+    for (dim, value) in middle.iter_mut().enumerate() {
+        let mut column: Vec<f32> = marks.iter().map(|m| m[dim]).collect();
+        column.sort_by(f32::total_cmp);
+        let mid = column.len() / 2;
+        *value = if column.len().is_multiple_of(2) {
+            (column[mid - 1] + column[mid]) / 2.0
+        } else {
+            column[mid]
+        };
+    }
+    middle
+}
+
+// Greedily split marks into clusters, largest first. Each cluster is seeded by the
+// mark that is close to the most other unclaimed marks.
+fn cluster<T>(marks: &[T], close: impl Fn(&T, &T) -> bool) -> Vec<Vec<usize>> {
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    let mut unclaimed: Vec<usize> = (0..marks.len()).collect();
+
+    while !unclaimed.is_empty() {
+        let members = unclaimed
+            .iter()
+            .map(|&seed| {
+                unclaimed
+                    .iter()
+                    .copied()
+                    .filter(|&other| other == seed || close(&marks[seed], &marks[other]))
+                    .collect::<Vec<usize>>()
+            })
+            .enumerate()
+            .max_by_key(|(i, members)| (members.len(), std::cmp::Reverse(*i)))
+            .map(|(_, members)| members)
+            .unwrap();
+
+        unclaimed.retain(|i| !members.contains(i));
+        clusters.push(members);
+    }
+    clusters
+}
+
+fn mean<'a, const N: usize>(marks: impl Iterator<Item = &'a [f32; N]>) -> [f32; N] {
+    let mut sums = [0.0; N];
+    let mut count = 0;
+    for mark in marks {
+        count += 1;
+        for (sum, value) in sums.iter_mut().zip(mark) {
+            *sum += value;
+        }
+    }
+    sums.map(|sum| sum / count as f32)
+}
+
+// Intersection over union of two boxes given as [left, top, right, bottom]
+fn iou(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    let width = (a[2].min(b[2]) - a[0].max(b[0])).max(0.0);
+    let height = (a[3].min(b[3]) - a[1].max(b[1])).max(0.0);
+    let intersection = width * height;
+    let union = (a[2] - a[0]) * (a[3] - a[1]) + (b[2] - b[0]) * (b[3] - b[1]) - intersection;
+    if union > 0.0 {
+        intersection / union
+    } else if a == b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn distance(a: &[f32; 2], b: &[f32; 2]) -> f32 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+fn outliers_note(discarded: usize) -> String {
+    match discarded {
+        0 => "".to_string(),
+        1 => ", 1 discarded as an outlier".to_string(),
+        _ => format!(", {} discarded as outliers", discarded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selects(values: &[&str]) -> Vec<FlatField> {
+        values
+            .iter()
+            .map(|v| FlatField::Select {
+                value: v.to_string(),
+            })
+            .collect()
+    }
+
+    fn texts(values: &[&str]) -> Vec<FlatField> {
+        values
+            .iter()
+            .map(|v| FlatField::Text {
+                value: v.to_string(),
+            })
+            .collect()
+    }
+
+    fn lists(values: &[&[&str]]) -> Vec<FlatField> {
+        values
+            .iter()
+            .map(|vs| FlatField::List {
+                values: vs.iter().map(|v| v.to_string()).collect(),
+                value: vs.join(", "),
+            })
+            .collect()
+    }
+
+    fn points(coords: &[(i32, i32)]) -> Vec<FlatField> {
+        coords
+            .iter()
+            .map(|&(x, y)| FlatField::Point { x, y })
+            .collect()
+    }
+
+    fn boxes(edges: &[[i32; 4]]) -> Vec<FlatField> {
+        edges
+            .iter()
+            .map(|&[left, top, right, bottom]| FlatField::Box_ {
+                left,
+                top,
+                right,
+                bottom,
+            })
+            .collect()
+    }
+
+    fn value_of(field: &ReconciledField) -> (ReconciledFlag, String, String) {
+        let value = match field {
+            ReconciledField::List { value, .. }
+            | ReconciledField::Select { value, .. }
+            | ReconciledField::Text { value, .. } => value.clone(),
+            _ => panic!("Not a text field: {:?}", field),
+        };
+        let result = field.result();
+        (result.flag, value, result.notes.clone())
+    }
+
+    fn select(values: &[&str]) -> (ReconciledFlag, String, String) {
+        let fields = selects(values);
+        value_of(&reconcile_select(fields.iter().collect()))
+    }
+
+    fn text(values: &[&str]) -> (ReconciledFlag, String, String) {
+        let fields = texts(values);
+        value_of(&reconcile_text(fields.iter().collect()))
+    }
+
+    fn list(values: &[&[&str]]) -> (ReconciledFlag, String, String) {
+        let fields = lists(values);
+        value_of(&reconcile_list(fields.iter().collect(), values.len()))
+    }
+
+    fn point(coords: &[(i32, i32)], strategy: MarkStrategy) -> (i32, i32, String) {
+        let fields = points(coords);
+        match reconcile_points(fields.iter().collect(), strategy) {
+            ReconciledField::Point { x, y, result } => (x, y, result.notes),
+            field => panic!("Not a point: {:?}", field),
+        }
+    }
+
+    #[test]
+    fn median_takes_the_middle_of_each_coordinate() {
+        let coords = [(0, 40), (10, 0), (100, 20)];
+        let (x, y, notes) = point(&coords, MarkStrategy::Median);
+        assert_eq!((x, y), (10, 20));
+        assert_eq!(notes, "There are 3 point records");
+    }
+
+    #[test]
+    fn median_of_an_even_count_averages_the_middle_two() {
+        let coords = [(0, 0), (10, 10), (20, 20), (100, 100)];
+        let (x, y, _) = point(&coords, MarkStrategy::Median);
+        assert_eq!((x, y), (15, 15));
+    }
+
+    #[test]
+    fn trimmed_mean_drops_the_marks_farthest_from_the_median() {
+        let coords = [(10, 10), (11, 11), (12, 12), (13, 13), (100, 100)];
+        let (x, y, notes) = point(&coords, MarkStrategy::TrimmedMean);
+        assert_eq!((x, y), (12, 12));
+        assert_eq!(notes, "There are 5 point records, 2 discarded as outliers");
+    }
+
+    #[test]
+    fn trimmed_mean_keeps_everything_with_too_few_marks() {
+        let coords = [(0, 0), (10, 10), (100, 100)];
+        let (x, y, notes) = point(&coords, MarkStrategy::TrimmedMean);
+        assert_eq!((x, y), (37, 37));
+        assert_eq!(notes, "There are 3 point records");
+    }
+
+    #[test]
+    fn cluster_averages_the_largest_group_of_points() {
+        let coords = [(0, 0), (10, 0), (20, 0), (500, 500)];
+        let (x, y, notes) = point(&coords, MarkStrategy::Cluster);
+        assert_eq!((x, y), (10, 0));
+        assert_eq!(
+            notes,
+            "There are 4 point records, 1 discarded as an outlier"
+        );
+    }
+
+    #[test]
+    fn cluster_averages_the_largest_group_of_boxes() {
+        let fields = boxes(&[
+            [0, 0, 10, 10],
+            [1, 1, 11, 11],
+            [300, 300, 310, 310],
+            [2, 0, 12, 10],
+        ]);
+        match reconcile_boxes(fields.iter().collect(), MarkStrategy::Cluster) {
+            ReconciledField::Box_ {
+                left,
+                top,
+                right,
+                bottom,
+                result,
+            } => {
+                assert_eq!((left, top, right, bottom), (1, 0, 11, 10));
+                assert_eq!(result.flag, ReconciledFlag::Ok);
+                assert_eq!(
+                    result.notes,
+                    "There are 4 box records, 1 discarded as an outlier"
+                );
+            }
+            field => panic!("Not a box: {:?}", field),
+        }
+    }
+
+    #[test]
+    fn select_flags() {
+        assert_eq!(select(&[]).0, ReconciledFlag::Empty);
+
+        let (flag, value, notes) = select(&["", " "]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::AllBlank, ""));
+        assert_eq!(notes, "All 2 records are blank");
+
+        let (flag, value, notes) = select(&["a", ""]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::OnlyOne, "a"));
+        assert_eq!(notes, "Only 1 transcriber in 2 records, 1 record blank");
+
+        let (flag, value, notes) = select(&["a", "b"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::NoMatch, ""));
+        assert_eq!(notes, "No select match on 2 records");
+
+        let (flag, value, notes) = select(&["a", "a"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::Unanimous, "a"));
+        assert_eq!(notes, "Unanimous match, 2 of 2 records");
+
+        let (flag, value, notes) = select(&["b", "a", "b"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::Majority, "b"));
+        assert_eq!(notes, "2 of 3 records matched");
+
+        let (flag, value, notes) = select(&["a", "b", "b", "a"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::Majority, "a"));
+        assert_eq!(notes, "Match is a tie between the top 2 on 4 records");
+    }
+
+    #[test]
+    fn text_flags() {
+        assert_eq!(text(&[]).0, ReconciledFlag::Empty);
+
+        let (flag, _, notes) = text(&["", "  "]);
+        assert_eq!(flag, ReconciledFlag::AllBlank);
+        assert_eq!(notes, "All 2 records are blank");
+
+        let (flag, value, _) = text(&["", "A Smith"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::OnlyOne, "A Smith"));
+
+        let (flag, value, notes) = text(&["A  Smith", "a smith"]);
+        assert_eq!(
+            (flag, value.as_str()),
+            (ReconciledFlag::Unanimous, "A Smith")
+        );
+        assert_eq!(notes, "Unanimous match, 2 of 2 records");
+
+        let (flag, value, notes) = text(&["B Jones", "a smith", "A Smith"]);
+        assert_eq!(
+            (flag, value.as_str()),
+            (ReconciledFlag::Majority, "a smith")
+        );
+        assert_eq!(notes, "2 of 3 records matched");
+
+        let (flag, value, notes) = text(&["Alfred Smith", "Alfred Smyth"]);
+        assert_eq!(
+            (flag, value.as_str()),
+            (ReconciledFlag::Fuzzy, "Alfred Smith")
+        );
+        assert!(notes.starts_with("Fuzzy match on 2 records with score 9"));
+
+        let (flag, value, notes) = text(&["apple", "zebra crossing"]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::NoMatch, ""));
+        assert_eq!(notes, "No text match on 2 records");
+    }
+
+    #[test]
+    fn list_flags() {
+        assert_eq!(list(&[]).0, ReconciledFlag::Empty);
+
+        let (flag, _, notes) = list(&[&[], &[]]);
+        assert_eq!(flag, ReconciledFlag::AllBlank);
+        assert_eq!(notes, "No options chosen in 2 records");
+
+        let (flag, value, notes) = list(&[&["forest"], &["forest"]]);
+        assert_eq!(
+            (flag, value.as_str()),
+            (ReconciledFlag::Unanimous, "forest")
+        );
+        assert_eq!(notes, "Unanimous match (forest: 2 of 2)");
+
+        let (flag, value, notes) = list(&[&["swamp", "forest"], &["forest"]]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::Majority, "forest"));
+        assert_eq!(notes, "Majority match (forest: 2 of 2, swamp: 1 of 2)");
+
+        let (flag, value, notes) = list(&[&["forest"], &["swamp"]]);
+        assert_eq!((flag, value.as_str()), (ReconciledFlag::NoMatch, ""));
+        assert_eq!(
+            notes,
+            "No option chosen by a majority (forest: 1 of 2, swamp: 1 of 2)"
+        );
+    }
+}
@@ -1,8 +1,16 @@
-// use crate::flat::Flat;
-// use crate::flatten::SUBJECT_ID;
+use crate::flat;
+use crate::flat::{Flat, FlatField};
+use crate::output;
+use crate::reconcile;
+use crate::reconcile::ReconcileOptions;
+use csv::Writer;
 use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum ReconciledFlag {
     Error,
     Ok,
@@ -15,14 +23,22 @@ pub enum ReconciledFlag {
     Fuzzy,
 }
 
-#[derive(Debug)]
+impl fmt::Display for ReconciledFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ReconciledResult {
-    // flag: ReconciledFlag,
-    // notes: String,
+    pub flag: ReconciledFlag,
+    pub notes: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum ReconciledField {
+    #[serde(rename = "box")]
     Box_ {
         left: i32,
         top: i32,
@@ -51,6 +67,11 @@ pub enum ReconciledField {
         units: String,
         result: ReconciledResult,
     },
+    List {
+        values: Vec<String>,
+        value: String,
+        result: ReconciledResult,
+    },
     NoOp {
         value: String,
         result: ReconciledResult,
@@ -66,7 +87,7 @@ pub enum ReconciledField {
     },
     Select {
         value: String,
-        flag: ReconciledResult,
+        result: ReconciledResult,
     },
     Text {
         value: String,
@@ -74,15 +95,30 @@ pub enum ReconciledField {
     },
 }
 
+impl ReconciledField {
+    pub fn result(&self) -> &ReconciledResult {
+        match self {
+            ReconciledField::Box_ { result, .. }
+            | ReconciledField::Length { result, .. }
+            | ReconciledField::RulerLength { result, .. }
+            | ReconciledField::List { result, .. }
+            | ReconciledField::NoOp { result, .. }
+            | ReconciledField::Point { result, .. }
+            | ReconciledField::Same { result, .. }
+            | ReconciledField::Select { result, .. }
+            | ReconciledField::Text { result, .. } => result,
+        }
+    }
+}
+
 pub type ReconciledRow = IndexMap<String, ReconciledField>;
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Reconciled {
     pub workflow_id: String,
     pub workflow_name: String,
-    // columns: IndexMap<String, Vec<ReconciledField>>,
-    // types: IndexMap<String, ReconciledField>,
-    // row_count: usize,
+    columns: IndexMap<String, ReconciledField>,
+    rows: Vec<ReconciledRow>,
 }
 
 impl Reconciled {
@@ -90,11 +126,306 @@ impl Reconciled {
         Reconciled {
             workflow_id: workflow_id.to_string(),
             workflow_name: workflow_name.to_string(),
-            // columns: IndexMap::new(),
-            // types: IndexMap::new(),
-            // row_count: 0,
+            columns: IndexMap::new(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn reconcile(&mut self, flat: &Flat, options: &ReconcileOptions) {
+        // Repeated marks with the same tool are spread across numbered columns
+        let mut columns: IndexMap<&str, Vec<&str>> = IndexMap::new();
+        for (column, field_type) in flat.columns() {
+            let base = match field_type {
+                FlatField::Box_ { .. } | FlatField::Length { .. } | FlatField::Point { .. } => {
+                    flat::mark_base(column)
+                }
+                _ => column,
+            };
+            columns.entry(base).or_default().push(column);
+        }
+
+        for (_, group) in flat.groups() {
+            let mut row = ReconciledRow::new();
+
+            for (base, instances) in columns.iter() {
+                let field_type = &flat.columns()[instances[0]];
+                let fields: Vec<&FlatField> = group
+                    .iter()
+                    .flat_map(|r| instances.iter().filter_map(|column| r.get(*column)))
+                    .collect();
+
+                if instances.len() == 1 {
+                    if let Some(field) =
+                        reconcile_column(base, field_type, fields, group.len(), options)
+                    {
+                        row.insert(base.to_string(), field);
+                    }
+                    continue;
+                }
+
+                // Sort the marks into the objects they outline before reconciling them
+                for (i, cluster) in reconcile::cluster_marks(fields).into_iter().enumerate() {
+                    if let Some(field) =
+                        reconcile_column(base, field_type, cluster, group.len(), options)
+                    {
+                        row.insert(flat::mark_key(base, i + 1), field);
+                    }
+                }
+            }
+            reconcile::convert_lengths(&mut row);
+            self.add_row(row);
+        }
+
+        self.sort_columns(&columns);
+
+        // Subjects with fewer mark clusters than others still get a result saying so
+        for row in self.rows.iter_mut() {
+            for column in self.columns.keys() {
+                if row.contains_key(column) {
+                    continue;
+                }
+                let base = flat::mark_base(column);
+                if let Some(instances) = columns.get(base).filter(|i| i.len() > 1) {
+                    let field_type = &flat.columns()[instances[0]];
+                    if let Some(field) = reconcile_column(base, field_type, vec![], 0, options) {
+                        row.insert(column.clone(), field);
+                    }
+                }
+            }
+        }
+    }
+
+    // Columns come in the flattened column order no matter which subject came first,
+    // with the numbered mark clusters right after their first cluster
+    fn sort_columns(&mut self, bases: &IndexMap<&str, Vec<&str>>) {
+        let key = |column: &str| {
+            let base = if bases.contains_key(column) {
+                column
+            } else {
+                flat::mark_base(column)
+            };
+            let mark: usize = column[base.len()..]
+                .trim_start_matches(" #")
+                .parse()
+                .unwrap_or(1);
+            (bases.get_index_of(base).unwrap_or(bases.len()), mark)
+        };
+        self.columns.sort_by(|a, _, b, _| key(a).cmp(&key(b)));
+    }
+
+    pub fn columns(&self) -> &IndexMap<String, ReconciledField> {
+        &self.columns
+    }
+
+    pub fn rows(&self) -> &[ReconciledRow] {
+        &self.rows
+    }
+
+    pub fn add_row(&mut self, row: ReconciledRow) {
+        for (column, field) in row.iter() {
+            if !self.columns.contains_key(column) {
+                self.columns.insert(column.to_owned(), field.clone());
+            }
         }
+        self.rows.push(row);
+    }
+
+    pub fn write_csv(&self, csv_path: &Path) -> Result<(), Box<dyn Error>> {
+        self.write_records(csv_path, false)
+    }
+
+    // Same as the reconciled CSV but every reconciled column is followed by how
+    // we got its value, so reviewers can see why a value was chosen
+    pub fn write_explanations_csv(&self, csv_path: &Path) -> Result<(), Box<dyn Error>> {
+        self.write_records(csv_path, true)
     }
 
-    // pub fn reconcile(&mut self, flat: &Flat) {}
+    fn write_records(&self, csv_path: &Path, explain: bool) -> Result<(), Box<dyn Error>> {
+        output::write_atomic(csv_path, |output| {
+            let mut writer = Writer::from_writer(output);
+
+            writer.write_record(self.csv_header(explain))?;
+
+            for row in self.rows.iter() {
+                writer.write_record(self.csv_row(row, explain))?;
+            }
+
+            writer.flush()?;
+            Ok(())
+        })
+    }
+
+    fn csv_header(&self, explain: bool) -> Vec<String> {
+        let mut output: Vec<String> = Vec::new();
+
+        for (column, field_type) in self.columns.iter() {
+            match &field_type {
+                ReconciledField::Box_ { .. } => {
+                    output.push(format!("{}: left", column));
+                    output.push(format!("{}: top", column));
+                    output.push(format!("{}: right", column));
+                    output.push(format!("{}: bottom", column));
+                }
+                ReconciledField::Length { .. } => {
+                    output.push(format!("{}: x1", column));
+                    output.push(format!("{}: y1", column));
+                    output.push(format!("{}: x2", column));
+                    output.push(format!("{}: y2", column));
+                    output.push(format!("{}: pixel length", column));
+                    output.push(format!("{}: length", column));
+                    output.push(format!("{}: units", column));
+                }
+                ReconciledField::RulerLength { .. } => {
+                    output.push(format!("{}: x1", column));
+                    output.push(format!("{}: y1", column));
+                    output.push(format!("{}: x2", column));
+                    output.push(format!("{}: y2", column));
+                    output.push(format!("{}: pixel length", column));
+                    output.push(format!("{}: length", column));
+                    output.push(format!("{}: units", column));
+                    output.push(format!("{}: factor", column));
+                }
+                ReconciledField::Point { .. } => {
+                    output.push(format!("{}: x", column));
+                    output.push(format!("{}: y", column));
+                }
+                ReconciledField::List { .. }
+                | ReconciledField::NoOp { .. }
+                | ReconciledField::Same { .. }
+                | ReconciledField::Select { .. }
+                | ReconciledField::Text { .. } => {
+                    output.push(column.to_string());
+                }
+            }
+            if explain && is_explained(field_type) {
+                output.push(format!("{}: flag", column));
+                output.push(format!("{}: explanation", column));
+            }
+        }
+        output
+    }
+
+    fn csv_row(&self, row: &ReconciledRow, explain: bool) -> Vec<String> {
+        let mut output: Vec<String> = Vec::new();
+
+        for (header, field_type) in self.columns.iter() {
+            match row.get(header) {
+                None => {
+                    let width = column_width(field_type);
+                    output.extend(std::iter::repeat_n("".to_string(), width));
+                }
+                // Don't write made up coordinates when nobody drew anything
+                Some(field) if field.result().flag == ReconciledFlag::Empty => {
+                    let width = column_width(field_type);
+                    output.extend(std::iter::repeat_n("".to_string(), width));
+                }
+                Some(ReconciledField::Box_ {
+                    left,
+                    top,
+                    right,
+                    bottom,
+                    ..
+                }) => {
+                    output.push(format!("{}", left));
+                    output.push(format!("{}", top));
+                    output.push(format!("{}", right));
+                    output.push(format!("{}", bottom));
+                }
+                Some(ReconciledField::Length {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    length,
+                    pixel_length,
+                    units,
+                    ..
+                }) => {
+                    output.push(format!("{}", x1));
+                    output.push(format!("{}", y1));
+                    output.push(format!("{}", x2));
+                    output.push(format!("{}", y2));
+                    output.push(format!("{:.2}", pixel_length));
+                    output.push(format!("{:.2}", length));
+                    output.push(units.clone());
+                }
+                Some(ReconciledField::RulerLength {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    length,
+                    pixel_length,
+                    factor,
+                    units,
+                    ..
+                }) => {
+                    output.push(format!("{}", x1));
+                    output.push(format!("{}", y1));
+                    output.push(format!("{}", x2));
+                    output.push(format!("{}", y2));
+                    output.push(format!("{:.2}", pixel_length));
+                    output.push(format!("{:.2}", length));
+                    output.push(units.clone());
+                    output.push(format!("{:.4}", factor));
+                }
+                Some(ReconciledField::Point { x, y, .. }) => {
+                    output.push(format!("{}", x));
+                    output.push(format!("{}", y));
+                }
+                Some(ReconciledField::List { value, .. })
+                | Some(ReconciledField::NoOp { value, .. })
+                | Some(ReconciledField::Same { value, .. })
+                | Some(ReconciledField::Select { value, .. })
+                | Some(ReconciledField::Text { value, .. }) => {
+                    output.push(value.clone());
+                }
+            }
+            if explain && is_explained(field_type) {
+                match row.get(header) {
+                    Some(field) => {
+                        output.push(field.result().flag.to_string());
+                        output.push(field.result().notes.clone());
+                    }
+                    None => output.extend(std::iter::repeat_n("".to_string(), 2)),
+                }
+            }
+        }
+        output
+    }
+}
+
+fn reconcile_column(
+    column: &str,
+    field_type: &FlatField,
+    fields: Vec<&FlatField>,
+    row_count: usize,
+    options: &ReconcileOptions,
+) -> Option<ReconciledField> {
+    let field = match field_type {
+        FlatField::Same { .. } => reconcile::reconcile_same(fields),
+        FlatField::Select { .. } => reconcile::reconcile_select(fields),
+        FlatField::Text { .. } => reconcile::reconcile_text(fields),
+        FlatField::List { .. } => reconcile::reconcile_list(fields, row_count),
+        FlatField::Box_ { .. } => reconcile::reconcile_boxes(fields, options.strategy(column)),
+        FlatField::Length { .. } => reconcile::reconcile_lengths(fields, column),
+        FlatField::Point { .. } => reconcile::reconcile_points(fields, options.strategy(column)),
+        FlatField::NoOp { .. } => return None,
+    };
+    Some(field)
+}
+
+// Columns copied from the classifications, like the subject ID, need no explanation
+fn is_explained(field_type: &ReconciledField) -> bool {
+    !matches!(field_type, ReconciledField::Same { .. })
+}
+
+fn column_width(field_type: &ReconciledField) -> usize {
+    match field_type {
+        ReconciledField::Box_ { .. } => 4,
+        ReconciledField::Length { .. } => 7,
+        ReconciledField::RulerLength { .. } => 8,
+        ReconciledField::Point { .. } => 2,
+        _ => 1,
+    }
 }
@@ -0,0 +1,339 @@
+use crate::flat;
+use crate::flat::{Flat, FlatField, FlatRow};
+use crate::flatten;
+use crate::output;
+use crate::reconciled::{Reconciled, ReconciledField, ReconciledFlag};
+use indexmap::IndexMap;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+
+const FLAGS: [ReconciledFlag; 9] = [
+    ReconciledFlag::Unanimous,
+    ReconciledFlag::Majority,
+    ReconciledFlag::Fuzzy,
+    ReconciledFlag::OnlyOne,
+    ReconciledFlag::Ok,
+    ReconciledFlag::NoMatch,
+    ReconciledFlag::AllBlank,
+    ReconciledFlag::Empty,
+    ReconciledFlag::Error,
+];
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1.5em; color: #222; }
+h1 { margin-bottom: 0.2em; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; vertical-align: top; text-align: left; }
+th { background: #eee; }
+#subjects th { cursor: pointer; position: sticky; top: 0; }
+#subjects th.asc::after { content: " \25B2"; }
+#subjects th.desc::after { content: " \25BC"; }
+.value { font-weight: bold; }
+.notes { font-size: 0.8em; color: #555; }
+.raw { font-size: 0.8em; margin: 0.2em 0 0 0; padding-left: 1.2em; color: #555; }
+.Unanimous { background: #d9f2d9; }
+.Majority { background: #e8f5d0; }
+.Fuzzy { background: #fff3c4; }
+.OnlyOne { background: #fde4c8; }
+.NoMatch, .Error { background: #f8d0d0; }
+.AllBlank, .Empty { background: #f4f4f4; }
+.controls { margin: 1em 0; }
+.controls input { width: 20em; }
+"#;
+
+const SCRIPT: &str = r#"
+const table = document.getElementById("subjects");
+const body = table.tBodies[0];
+const search = document.getElementById("search");
+const flag = document.getElementById("flag");
+
+function filterRows() {
+  const text = search.value.toLowerCase();
+  for (const row of body.rows) {
+    const hasText = row.textContent.toLowerCase().includes(text);
+    const hasFlag = !flag.value || row.querySelector("td." + flag.value) !== null;
+    row.style.display = hasText && hasFlag ? "" : "none";
+  }
+}
+
+function sortRows(th) {
+  const index = th.cellIndex;
+  const asc = !th.classList.contains("asc");
+  for (const other of th.parentNode.cells) { other.classList.remove("asc", "desc"); }
+  th.classList.add(asc ? "asc" : "desc");
+  const key = row => row.cells[index].dataset.sort || row.cells[index].textContent;
+  const rows = Array.from(body.rows);
+  rows.sort((a, b) => {
+    const [x, y] = [key(a), key(b)];
+    const diff = (x !== "" && y !== "" && !isNaN(x) && !isNaN(y))
+      ? Number(x) - Number(y) : x.localeCompare(y);
+    return asc ? diff : -diff;
+  });
+  rows.forEach(row => body.appendChild(row));
+}
+
+search.addEventListener("input", filterRows);
+flag.addEventListener("change", filterRows);
+for (const th of table.tHead.rows[0].cells) { th.addEventListener("click", () => sortRows(th)); }
+"#;
+
+// Write a self-contained HTML report so people can audit the reconciliation
+pub fn write_html(
+    html_path: &Path,
+    flat: &Flat,
+    reconciled: &Reconciled,
+) -> Result<(), Box<dyn Error>> {
+    let mut html = String::new();
+
+    let title = format!("Summary of {}", reconciled.workflow_name);
+    writeln!(html, "<!DOCTYPE html>\n<html lang=\"en\">\n<head>")?;
+    writeln!(html, "<meta charset=\"utf-8\">")?;
+    writeln!(html, "<title>{}</title>", escape(&title))?;
+    writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE)?;
+    writeln!(html, "<h1>{}</h1>", escape(&title))?;
+
+    write_counts(&mut html, flat, reconciled)?;
+    write_flags(&mut html, reconciled)?;
+    write_subjects(&mut html, flat, reconciled)?;
+
+    writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT)?;
+
+    output::write_atomic(html_path, |output| {
+        output.write_all(html.as_bytes())?;
+        Ok(())
+    })
+}
+
+fn write_counts(
+    html: &mut String,
+    flat: &Flat,
+    reconciled: &Reconciled,
+) -> Result<(), Box<dyn Error>> {
+    let volunteers: BTreeSet<&str> = flat
+        .rows()
+        .iter()
+        .filter_map(|row| match row.get(flatten::USER_NAME) {
+            Some(FlatField::NoOp { value }) => Some(value.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let counts = [
+        ("Workflow name", reconciled.workflow_name.clone()),
+        ("Workflow ID", reconciled.workflow_id.clone()),
+        ("Subjects", flat.subject_count().to_string()),
+        ("Classifications", flat.classification_count().to_string()),
+        ("Volunteers", volunteers.len().to_string()),
+        ("Rejected rows", flat.rejects().len().to_string()),
+    ];
+
+    writeln!(html, "<table id=\"counts\">")?;
+    for (label, count) in counts {
+        writeln!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            label,
+            escape(&count)
+        )?;
+    }
+    writeln!(html, "</table>")?;
+    Ok(())
+}
+
+fn write_flags(html: &mut String, reconciled: &Reconciled) -> Result<(), Box<dyn Error>> {
+    writeln!(html, "<h2>Results by column</h2>\n<table id=\"flags\">")?;
+
+    write!(html, "<tr><th>Column</th>")?;
+    for flag in FLAGS {
+        write!(html, "<th class=\"{}\">{}</th>", flag, flag)?;
+    }
+    writeln!(html, "</tr>")?;
+
+    for column in reconciled.columns().keys() {
+        let mut counts: IndexMap<ReconciledFlag, usize> = FLAGS.iter().map(|f| (*f, 0)).collect();
+        for row in reconciled.rows() {
+            if let Some(field) = row.get(column) {
+                *counts.entry(field.result().flag).or_insert(0) += 1;
+            }
+        }
+
+        write!(html, "<tr><td>{}</td>", escape(column))?;
+        for count in counts.values() {
+            write!(html, "<td>{}</td>", count)?;
+        }
+        writeln!(html, "</tr>")?;
+    }
+    writeln!(html, "</table>")?;
+    Ok(())
+}
+
+fn write_subjects(
+    html: &mut String,
+    flat: &Flat,
+    reconciled: &Reconciled,
+) -> Result<(), Box<dyn Error>> {
+    let groups = flat.group();
+
+    writeln!(html, "<h2>Subjects</h2>\n<div class=\"controls\">")?;
+    writeln!(
+        html,
+        "<input id=\"search\" type=\"search\" placeholder=\"Filter subjects\">"
+    )?;
+    write!(
+        html,
+        "<select id=\"flag\"><option value=\"\">Any result</option>"
+    )?;
+    for flag in FLAGS {
+        write!(html, "<option value=\"{}\">{}</option>", flag, flag)?;
+    }
+    writeln!(html, "</select>\n</div>")?;
+
+    writeln!(html, "<table id=\"subjects\">\n<thead><tr>")?;
+    write!(html, "<th>Volunteers</th>")?;
+    for column in reconciled.columns().keys() {
+        write!(html, "<th>{}</th>", escape(column))?;
+    }
+    writeln!(html, "</tr></thead>\n<tbody>")?;
+
+    for row in reconciled.rows() {
+        let subject_id = match row.get(flatten::SUBJECT_ID) {
+            Some(ReconciledField::Same { value, .. }) => value.as_str(),
+            _ => "",
+        };
+        let group: &[&FlatRow] = groups.get(subject_id).map_or(&[], |g| g.as_slice());
+
+        write!(html, "<tr><td>{}</td>", group.len())?;
+        for column in reconciled.columns().keys() {
+            match row.get(column) {
+                Some(field) => write_cell(html, column, field, group, flat)?,
+                None => write!(html, "<td></td>")?,
+            }
+        }
+        writeln!(html, "</tr>")?;
+    }
+    writeln!(html, "</tbody>\n</table>")?;
+    Ok(())
+}
+
+// The reconciled value, how we got it, and what the volunteers actually entered
+fn write_cell(
+    html: &mut String,
+    column: &str,
+    field: &ReconciledField,
+    group: &[&FlatRow],
+    flat: &Flat,
+) -> Result<(), Box<dyn Error>> {
+    let result = field.result();
+    let value = reconciled_value(field);
+
+    write!(
+        html,
+        "<td class=\"{}\" data-sort=\"{}\"><div class=\"value\">{}</div>",
+        result.flag,
+        escape(&value),
+        escape(&value)
+    )?;
+    write!(
+        html,
+        "<div class=\"notes\">{}: {}</div>",
+        result.flag,
+        escape(&result.notes)
+    )?;
+
+    // Marks split over numbered columns were reconciled together
+    let is_mark = matches!(
+        field,
+        ReconciledField::Box_ { .. }
+            | ReconciledField::Length { .. }
+            | ReconciledField::RulerLength { .. }
+            | ReconciledField::Point { .. }
+    );
+    let sources: Vec<&String> = flat
+        .columns()
+        .keys()
+        .filter(|c| *c == column || (is_mark && flat::mark_base(c) == flat::mark_base(column)))
+        .collect();
+
+    let raw: Vec<String> = group
+        .iter()
+        .flat_map(|row| sources.iter().filter_map(|c| row.get(*c)))
+        .map(raw_value)
+        .collect();
+
+    if !raw.is_empty() && !matches!(field, ReconciledField::Same { .. }) {
+        write!(html, "<ul class=\"raw\">")?;
+        for value in raw {
+            write!(html, "<li>{}</li>", escape(&value))?;
+        }
+        write!(html, "</ul>")?;
+    }
+    write!(html, "</td>")?;
+    Ok(())
+}
+
+fn reconciled_value(field: &ReconciledField) -> String {
+    if field.result().flag == ReconciledFlag::Empty {
+        return "".to_string();
+    }
+    match field {
+        ReconciledField::Box_ {
+            left,
+            top,
+            right,
+            bottom,
+            ..
+        } => format!("({}, {}) to ({}, {})", left, top, right, bottom),
+        ReconciledField::Length { length, units, .. }
+        | ReconciledField::RulerLength { length, units, .. }
+            if !units.is_empty() =>
+        {
+            format!("{:.2} {}", length, units)
+        }
+        ReconciledField::Length { pixel_length, .. }
+        | ReconciledField::RulerLength { pixel_length, .. } => {
+            format!("{:.2} pixels", pixel_length)
+        }
+        ReconciledField::Point { x, y, .. } => format!("({}, {})", x, y),
+        ReconciledField::List { value, .. }
+        | ReconciledField::NoOp { value, .. }
+        | ReconciledField::Same { value, .. }
+        | ReconciledField::Select { value, .. }
+        | ReconciledField::Text { value, .. } => value.clone(),
+    }
+}
+
+fn raw_value(field: &FlatField) -> String {
+    match field {
+        FlatField::Box_ {
+            left,
+            top,
+            right,
+            bottom,
+        } => format!("({}, {}) to ({}, {})", left, top, right, bottom),
+        FlatField::Length { x1, y1, x2, y2 } => format!("({}, {}) to ({}, {})", x1, y1, x2, y2),
+        FlatField::Point { x, y } => format!("({}, {})", x, y),
+        FlatField::List { value, .. }
+        | FlatField::NoOp { value }
+        | FlatField::Same { value }
+        | FlatField::Select { value }
+        | FlatField::Text { value } => value.clone(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,294 @@
+use crate::flatten::WorkflowVersion;
+use indexmap::IndexMap;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+// Known fields in the Zooniverse workflow export
+pub const DISPLAY_NAME: &str = "display_name";
+pub const FIRST_TASK: &str = "first_task";
+pub const MINOR_VERSION: &str = "minor_version";
+pub const STRINGS: &str = "strings";
+pub const TASKS: &str = "tasks";
+pub const VERSION: &str = "version";
+pub const WORKFLOW_ID: &str = "workflow_id";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ToolKind {
+    Box_,
+    Length,
+    Point,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Tool {
+    pub kind: ToolKind,
+    pub label: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Select {
+    pub label: String,
+    pub options: HashMap<String, String>, // Option value -> option label
+}
+
+#[derive(Clone, Debug)]
+pub enum TaskKind {
+    Combo { tasks: Vec<String> },
+    Drawing { tools: Vec<Tool> },
+    Dropdown { selects: Vec<Select> },
+    Multiple { answers: Vec<String> },
+    Single { answers: Vec<String> },
+    Text,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Task {
+    pub label: String,
+    pub next: Option<String>,
+    pub kind: TaskKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Workflow {
+    pub workflow_id: String,
+    pub workflow_name: String,
+    pub version: WorkflowVersion,
+    pub first_task: String,
+    pub tasks: IndexMap<String, Task>,
+}
+
+impl Workflow {
+    // Task IDs in the order volunteers see them: follow the "next" links from the
+    // first task, expanding combo tasks in place. Unreachable tasks go at the end.
+    pub fn task_order(&self) -> Vec<String> {
+        let mut order: Vec<String> = Vec::new();
+        let mut next = Some(self.first_task.clone());
+
+        while let Some(task_id) = next {
+            if order.contains(&task_id) || !self.tasks.contains_key(&task_id) {
+                break;
+            }
+            self.push_task(&task_id, &mut order);
+            next = self.tasks[&task_id].next.clone();
+        }
+
+        for task_id in self.tasks.keys() {
+            self.push_task(task_id, &mut order);
+        }
+        order
+    }
+
+    fn push_task(&self, task_id: &str, order: &mut Vec<String>) {
+        if order.iter().any(|t| t == task_id) {
+            return;
+        }
+        order.push(task_id.to_string());
+        if let Some(Task {
+            kind: TaskKind::Combo { tasks },
+            ..
+        }) = self.tasks.get(task_id)
+        {
+            for subtask in tasks {
+                self.push_task(subtask, order);
+            }
+        }
+    }
+
+    // Labels within a task that get their own columns, in workflow order
+    pub fn label_order(&self, task_id: &str) -> Vec<&str> {
+        match self.tasks.get(task_id).map(|task| &task.kind) {
+            Some(TaskKind::Drawing { tools }) => tools.iter().map(|t| t.label.as_str()).collect(),
+            Some(TaskKind::Dropdown { selects }) => {
+                selects.iter().map(|s| s.label.as_str()).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Every version of every workflow in a Zooniverse workflow export
+#[derive(Debug, Default)]
+pub struct Workflows {
+    versions: IndexMap<(String, WorkflowVersion), Workflow>,
+}
+
+impl Workflows {
+    pub fn insert(&mut self, workflow: Workflow) {
+        let key = (workflow.workflow_id.clone(), workflow.version);
+        self.versions.insert(key, workflow);
+    }
+
+    pub fn contains(&self, workflow_id: &str) -> bool {
+        self.versions.keys().any(|(id, _)| id == workflow_id)
+    }
+
+    // The newest version, for things that don't depend on a classification
+    pub fn latest(&self, workflow_id: &str) -> Option<&Workflow> {
+        self.versions
+            .values()
+            .filter(|w| w.workflow_id == workflow_id)
+            .max_by_key(|w| w.version)
+    }
+
+    // The version a classification was made with. Tasks only change with the major
+    // version so if the exact minor version is missing use the newest one with the
+    // same major version.
+    pub fn find(&self, workflow_id: &str, version: WorkflowVersion) -> Option<&Workflow> {
+        self.versions
+            .get(&(workflow_id.to_string(), version))
+            .or_else(|| {
+                self.versions
+                    .values()
+                    .filter(|w| w.workflow_id == workflow_id && w.version.major == version.major)
+                    .max_by_key(|w| w.version)
+            })
+    }
+}
+
+// Read every version of every workflow in a Zooniverse workflow export
+pub fn read_workflows(workflow_csv: &Path) -> Result<Workflows, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(workflow_csv)?;
+    let mut workflows = Workflows::default();
+
+    for deserialized_row in reader.deserialize() {
+        let raw_row: HashMap<String, String> = deserialized_row?;
+        workflows.insert(parse_workflow(&raw_row)?);
+    }
+    Ok(workflows)
+}
+
+fn parse_workflow(raw_row: &HashMap<String, String>) -> Result<Workflow, Box<dyn Error>> {
+    let get = |column: &str| raw_row.get(column).map_or("", |v| v.as_str());
+
+    let workflow_id = get(WORKFLOW_ID).to_string();
+    let version = format!("{}.{}", get(VERSION), get(MINOR_VERSION))
+        .parse()
+        .unwrap_or(WorkflowVersion { major: 0, minor: 0 });
+
+    let strings: HashMap<String, String> = match get(STRINGS) {
+        "" => HashMap::new(),
+        raw => serde_json::from_str(raw).map_err(|e| {
+            format!(
+                "Could not parse strings for workflow {}: {}",
+                workflow_id, e
+            )
+        })?,
+    };
+    let raw_tasks: IndexMap<String, Value> = match get(TASKS) {
+        "" => IndexMap::new(),
+        raw => serde_json::from_str(raw)
+            .map_err(|e| format!("Could not parse tasks for workflow {}: {}", workflow_id, e))?,
+    };
+
+    let tasks = raw_tasks
+        .iter()
+        .map(|(task_id, task)| (task_id.clone(), parse_task(task, &strings)))
+        .collect();
+
+    Ok(Workflow {
+        workflow_id,
+        workflow_name: get(DISPLAY_NAME).to_string(),
+        version,
+        first_task: get(FIRST_TASK).to_string(),
+        tasks,
+    })
+}
+
+fn parse_task(task: &Value, strings: &HashMap<String, String>) -> Task {
+    let label = ["instruction", "question"]
+        .iter()
+        .map(|key| resolve(&task[key], strings))
+        .find(|label| !label.is_empty())
+        .unwrap_or_default();
+
+    let answers = || -> Vec<String> {
+        list(&task["answers"])
+            .iter()
+            .map(|answer| resolve(&answer["label"], strings))
+            .collect()
+    };
+
+    let kind = match task["type"].as_str().unwrap_or_default() {
+        "combo" => TaskKind::Combo {
+            tasks: list(&task["tasks"])
+                .iter()
+                .filter_map(|t| t.as_str().map(|t| t.to_string()))
+                .collect(),
+        },
+        "drawing" => TaskKind::Drawing {
+            tools: list(&task["tools"])
+                .iter()
+                .map(|tool| Tool {
+                    kind: match tool["type"].as_str().unwrap_or_default() {
+                        "rectangle" => ToolKind::Box_,
+                        "line" => ToolKind::Length,
+                        "point" => ToolKind::Point,
+                        _ => ToolKind::Other,
+                    },
+                    label: resolve(&tool["label"], strings),
+                })
+                .collect(),
+        },
+        "dropdown" => TaskKind::Dropdown {
+            selects: list(&task["selects"])
+                .iter()
+                .map(|select| Select {
+                    label: resolve(&select["title"], strings),
+                    options: parse_options(&select["options"], strings),
+                })
+                .collect(),
+        },
+        "multiple" => TaskKind::Multiple { answers: answers() },
+        "single" => TaskKind::Single { answers: answers() },
+        "text" => TaskKind::Text,
+        _ => TaskKind::Other,
+    };
+
+    Task {
+        label,
+        next: task["next"].as_str().map(|next| next.to_string()),
+        kind,
+    }
+}
+
+// Dropdown options are grouped by the answer to an earlier select, "*" means any
+// answer. We don't care about the grouping only the value to label mapping.
+fn parse_options(options: &Value, strings: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut mapping = HashMap::new();
+    if let Value::Object(groups) = options {
+        for group in groups.values() {
+            for option in list(group) {
+                let value = match &option["value"] {
+                    Value::String(value) => value.clone(),
+                    Value::Null => continue,
+                    value => value.to_string(),
+                };
+                mapping.insert(value, resolve(&option["label"], strings));
+            }
+        }
+    }
+    mapping
+}
+
+// Labels are usually keys into the workflow strings, but older exports inline them
+fn resolve(key: &Value, strings: &HashMap<String, String>) -> String {
+    match key.as_str() {
+        Some(key) => strings
+            .get(key)
+            .map_or(key, |s| s.as_str())
+            .trim()
+            .to_string(),
+        None => "".to_string(),
+    }
+}
+
+fn list(value: &Value) -> &[Value] {
+    match value {
+        Value::Array(values) => values,
+        _ => &[],
+    }
+}